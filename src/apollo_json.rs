@@ -0,0 +1,161 @@
+use crate::CompareGFF;
+use serde_json::value::Value;
+use std::error::Error;
+
+impl CompareGFF {
+    /// Converts an Apollo JSON feature export into GFF records.
+    /// Accepts either an object with a `features` list, or a plain list of features.
+    pub(crate) fn apollo_json_records(
+        json: &Value,
+    ) -> Result<Vec<bio::io::gff::Record>, Box<dyn Error>> {
+        let features = match (json["features"].as_array(), json.as_array()) {
+            (Some(features), _) => features,
+            (None, Some(features)) => features,
+            _ => return Err(From::from("Apollo JSON: No features")),
+        };
+        let mut ret: Vec<bio::io::gff::Record> = vec![];
+        for feature in features {
+            Self::apollo_json_feature(feature, None, None, &mut ret)?;
+        }
+        Ok(ret)
+    }
+
+    /// Converts an Apollo JSON feature, and all its children, into GFF records.
+    /// Children inherit the sequence name of their parent if they do not have their own.
+    fn apollo_json_feature(
+        feature: &Value,
+        parent_id: Option<&str>,
+        parent_seqname: Option<&str>,
+        ret: &mut Vec<bio::io::gff::Record>,
+    ) -> Result<(), Box<dyn Error>> {
+        let id = match feature["uniquename"].as_str() {
+            Some(id) => id,
+            None => {
+                return Err(From::from(format!(
+                    "Apollo JSON: No uniquename in {}",
+                    feature
+                )))
+            }
+        };
+        let seqname = match feature["sequence"].as_str().or(parent_seqname) {
+            Some(seqname) => seqname,
+            None => return Err(From::from(format!("Apollo JSON: No sequence for {}", id))),
+        };
+        let location = &feature["location"];
+        let (fmin, fmax) = match (location["fmin"].as_u64(), location["fmax"].as_u64()) {
+            (Some(fmin), Some(fmax)) => (fmin, fmax),
+            _ => return Err(From::from(format!("Apollo JSON: No location for {}", id))),
+        };
+        let strand = match location["strand"].as_i64() {
+            Some(1) => "+",
+            Some(-1) => "-",
+            _ => ".",
+        };
+        let feature_type = match feature["type"]["name"].as_str() {
+            Some(feature_type) => feature_type,
+            None => return Err(From::from(format!("Apollo JSON: No type for {}", id))),
+        };
+        let frame = match feature["phase"].as_i64() {
+            Some(phase) => phase.to_string(),
+            None => ".".to_string(),
+        };
+
+        let mut attributes: Vec<(String, String)> = vec![("ID".to_string(), id.to_string())];
+        if let Some(parent_id) = parent_id.or(feature["parent_id"].as_str()) {
+            attributes.push(("Parent".to_string(), parent_id.to_string()));
+        }
+        if let Some(name) = feature["name"].as_str() {
+            attributes.push(("Name".to_string(), name.to_string()));
+        }
+        if let Some(owner) = feature["owner"].as_str() {
+            attributes.push(("owner".to_string(), owner.to_string()));
+        }
+        for key in &["date_creation", "date_last_modified"] {
+            match &feature[*key] {
+                Value::Null => {}
+                Value::String(s) => attributes.push((key.to_string(), s.to_string())),
+                v => attributes.push((key.to_string(), v.to_string())),
+            }
+        }
+        // Custom attributes (e.g. orig_id)
+        if let Some(properties) = feature["non_reserved_properties"].as_array() {
+            properties
+                .iter()
+                .filter_map(|p| Some((p["tag"].as_str()?, p["value"].as_str()?)))
+                .for_each(|(key, value)| attributes.push((key.to_string(), value.to_string())));
+        }
+        // Comments and other properties
+        if let Some(properties) = feature["properties"].as_array() {
+            properties
+                .iter()
+                .filter_map(|p| Some((p["type"]["name"].as_str()?, p["value"].as_str()?)))
+                .map(|(key, value)| match key {
+                    "comment" => ("Note", value),
+                    _ => (key, value),
+                })
+                .for_each(|(key, value)| attributes.push((key.to_string(), value.to_string())));
+        }
+        let mut json_attributes = json!({});
+        for (key, value) in attributes {
+            match json_attributes[&key].as_array_mut() {
+                Some(values) => values.push(json!(value)),
+                None => json_attributes[&key] = json!([value]),
+            }
+        }
+
+        let element = json!({
+            "seqname": seqname,
+            "source": ".",
+            "feature_type": feature_type,
+            "start": fmin + 1, // Apollo locations are 0-based, half-open
+            "end": fmax,
+            "score": ".",
+            "strand": strand,
+            "frame": frame,
+            "attributes": json_attributes,
+        });
+        ret.push(Self::gff_from_json(&element)?);
+
+        if let Some(children) = feature["children"].as_array() {
+            for child in children {
+                Self::apollo_json_feature(child, Some(id), Some(seqname), ret)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DataSet, GffSource};
+    use std::fs;
+
+    #[test]
+    fn apollo_json() {
+        let dir = "test/apollo_exon_move";
+        let mut cg = CompareGFF::new();
+        cg.load(GffSource::file(format!("{}/core.gff", dir)), DataSet::Base)
+            .unwrap();
+        cg.load(
+            GffSource::apollo_json(format!("{}/apollo.json", dir)),
+            DataSet::Target,
+        )
+        .unwrap();
+        let data2 = cg.data2.as_ref().unwrap();
+        assert_eq!(data2.len(), 8);
+        let exon = &data2["a1b2c3d4-0003"];
+        assert_eq!(*exon.start(), 1000);
+        assert_eq!(*exon.end(), 1200);
+        assert_eq!(exon.seqname(), "Scaffold0");
+        assert_eq!(CompareGFF::strand_symbol(exon), "+");
+
+        // Same diff as the equivalent Apollo GFF
+        let mut diff = cg.diff_apollo().unwrap();
+        let expected = fs::read_to_string(format!("{}/expected.json", dir)).unwrap();
+        let mut expected: Value = serde_json::from_str(&expected).unwrap();
+        CompareGFF::sort_comparison(&mut diff);
+        CompareGFF::sort_comparison(&mut expected);
+        assert_eq!(diff["changes"], expected["changes"]);
+    }
+}
@@ -0,0 +1,115 @@
+use crate::diff_features::ChangedFeature;
+use crate::{CompareGFF, DataSet};
+use serde_json::value::Value;
+use std::error::Error;
+
+/// Change types of the BED track, with their `itemRgb` color.
+const BED_COLORS: [(&str, &str); 5] = [
+    ("added", "0,160,0"),
+    ("removed", "200,0,0"),
+    ("boundary", "230,130,0"),
+    ("modified", "150,0,150"),
+    ("attribute", "0,90,200"),
+];
+
+impl CompareGFF {
+    /// Classifies a changed feature for the BED track: `added`, `removed`, `boundary` (start/end changed),
+    /// `modified` (other columns changed, e.g. type or strand), or `attribute` (only attributes changed).
+    fn bed_change_type(feature: &ChangedFeature) -> &str {
+        let columns = feature.changed_columns();
+        match feature.status() {
+            "modified" => {
+                if columns.contains(&"start") || columns.contains(&"end") {
+                    "boundary"
+                } else if !columns.is_empty() {
+                    "modified"
+                } else {
+                    "attribute"
+                }
+            }
+            status => status,
+        }
+    }
+
+    /// Renders the features affected by a diff (from `diff` or `diff_apollo`) as a BED9 track,
+    /// with `itemRgb` colored by change type (see `bed_change_type`).
+    /// Names are `ID:TYPE`. For boundary changes, the region covers both the old and the new location,
+    /// with the thick part showing the new one. The output is sorted, so it can be passed on to `bedToBigBed`.
+    /// `base` is the data set the diff applies to (the target for reversed diffs).
+    pub fn diff_bed(&self, diff: &Value, base: DataSet) -> Result<String, Box<dyn Error>> {
+        let mut lines: Vec<(String, u64, String)> = vec![];
+        for feature in self.diff_features(diff, base)? {
+            let change_type = Self::bed_change_type(&feature);
+            let color = BED_COLORS
+                .iter()
+                .find(|(name, _)| *name == change_type)
+                .map_or("0,0,0", |(_, color)| color);
+            let e = feature.element();
+            let (thick_start, thick_end) = (e.start().saturating_sub(1), *e.end());
+            let (start, end) = match &feature.old {
+                Some(old) if change_type == "boundary" && old.seqname() == e.seqname() => (
+                    thick_start.min(old.start().saturating_sub(1)),
+                    thick_end.max(*old.end()),
+                ),
+                _ => (thick_start, thick_end),
+            };
+            let line = format!(
+                "{}\t{}\t{}\t{}:{}\t0\t{}\t{}\t{}\t{}\n",
+                e.seqname(),
+                start,
+                end,
+                feature.id,
+                change_type,
+                Self::strand_symbol(e),
+                thick_start,
+                thick_end,
+                color
+            );
+            lines.push((e.seqname().to_string(), start, line));
+        }
+        lines.sort();
+        Ok(lines.into_iter().map(|(_, _, line)| line).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diff_bed(dir: &str, target: &str, apollo: bool) -> Vec<Vec<String>> {
+        let mut cg = CompareGFF::new();
+        cg.load_test_files(dir, "core.gff", target);
+        let diff = match apollo {
+            true => cg.diff_apollo().unwrap(),
+            false => cg.diff().unwrap(),
+        };
+        cg.diff_bed(&diff, DataSet::Base)
+            .unwrap()
+            .lines()
+            .map(|line| line.split('\t').map(|s| s.to_string()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn bed_boundary() {
+        let lines = diff_bed("alter_exon", "cap.gff", false);
+        let exon = lines.iter().find(|l| l[3] == "E0005:boundary").unwrap();
+        assert_eq!(exon.len(), 9);
+        assert_eq!(exon[0..3], ["Scaffold0", "799", "1200"]);
+        assert_eq!(exon[6..9], ["799", "1200", "230,130,0"]);
+    }
+
+    #[test]
+    fn bed_added_removed_attribute() {
+        let lines = diff_bed("added_exon", "cap.gff", false);
+        assert!(lines
+            .iter()
+            .any(|l| l[3].ends_with(":added") && l[8] == "0,160,0"));
+        let lines = diff_bed("remove_exon", "cap.gff", false);
+        assert!(lines
+            .iter()
+            .any(|l| l[3].ends_with(":removed") && l[8] == "200,0,0"));
+        let lines = diff_bed("apollo_attributes", "apollo.gff", true);
+        assert!(lines.iter().any(|l| l[3] == "G0001:attribute"));
+    }
+}
@@ -1,70 +1,521 @@
 extern crate getopts;
 extern crate gff_diff;
+#[macro_use]
+extern crate serde_json;
 
-use getopts::Options;
-use gff_diff::CompareGFF;
+use getopts::{Matches, Options};
+use gff_diff::{CompareGFF, CompressedWriter, DataSet, GffFormat, GffSource, Region};
+use serde_json::value::Value;
 use std::env;
-use std::io::{self};
+use std::error::Error;
+use std::fs;
+use std::io::{self, BufRead, Write};
 
-fn get_usage(program: &str, opts: Options) -> String {
-    let brief = format!("Usage: {} [options] FILE FILE2", program);
-    format!("{}", opts.usage(&brief))
+/// Output formats for diffs.
+const OUTPUT_FORMATS: &[&str] = &["json", "ndjson", "text", "gff", "bed", "tsv", "csv"];
+
+/// A subcommand, with its file arguments, and the allowed number of files.
+struct Command {
+    name: &'static str,
+    arguments: &'static str,
+    min_files: usize,
+    max_files: usize,
+    description: &'static str,
+}
+
+const COMMANDS: &[Command] = &[
+    Command {
+        name: "diff",
+        arguments: "FILE FILE2",
+        min_files: 2,
+        max_files: 2,
+        description: "generate the diff that turns FILE into FILE2",
+    },
+    Command {
+        name: "apply",
+        arguments: "BASE DIFF",
+        min_files: 2,
+        max_files: 2,
+        description: "apply a JSON or NDJSON diff to BASE, and write the resulting GFF",
+    },
+    Command {
+        name: "apollo",
+        arguments: "FULL APOLLO",
+        min_files: 2,
+        max_files: 2,
+        description:
+            "generate the diff between a full GFF file and an Apollo GFF file or JSON export",
+    },
+    Command {
+        name: "validate",
+        arguments: "DIFF [DIFF2 ...]",
+        min_files: 1,
+        max_files: usize::MAX,
+        description: "check diff files against the diff format schema",
+    },
+    Command {
+        name: "stats",
+        arguments: "FILE FILE2",
+        min_files: 2,
+        max_files: 2,
+        description: "summary statistics (JSON) of the diff that turns FILE into FILE2",
+    },
+    Command {
+        name: "merge",
+        arguments: "BASE OURS THEIRS",
+        min_files: 3,
+        max_files: 3,
+        description: "merge two independently edited versions of BASE",
+    },
+    Command {
+        name: "history",
+        arguments: "FILE FILE2 [FILE3 ...]",
+        min_files: 2,
+        max_files: usize::MAX,
+        description: "trace how features change across annotation versions",
+    },
+];
+
+fn get_usage(program: &str) -> String {
+    let mut ret = format!("Usage: {} COMMAND [options] FILES\n\nCommands:\n", program);
+    for command in COMMANDS {
+        let usage = format!("{} {}", command.name, command.arguments);
+        ret += &format!("    {:32}{}\n", usage, command.description);
+    }
+    ret + &format!(
+        "\nRun '{} COMMAND --help' for the options of a command.",
+        program
+    )
+}
+
+fn get_command_usage(program: &str, command: &Command, opts: &Options) -> String {
+    let brief = format!(
+        "Usage: {} {} [options] {}\n\n{}",
+        program, command.name, command.arguments, command.description
+    );
+    opts.usage(&brief)
+}
+
+/// Options for loading and comparing two files.
+fn add_compare_options(opts: &mut Options) {
+    opts.optflag("i", "issues", "record issues");
+    opts.optflag("r", "reverse", "reverse diff (turn FILE2 into FILE)");
+    opts.optopt(
+        "",
+        "input-format",
+        "format of the input files: gff3, gtf, gff2 (default: detect)",
+        "FORMAT",
+    );
+    opts.optopt(
+        "",
+        "region",
+        "only compare elements overlapping this region; uses tabix/CSI indices if present",
+        "SEQNAME:START-END",
+    );
+}
+
+/// Options for writing diffs.
+fn add_diff_output_options(opts: &mut Options) {
+    opts.optopt(
+        "f",
+        "format",
+        "output format of the diff: json (default), ndjson (one change per line, streamed), text, gff (changed features, with diff_status/diff_changes attributes), bed (BED9 change track), tsv/csv (one row per change)",
+        "FORMAT",
+    );
+    opts.optflag("", "color", "text: highlight changes with ANSI colors");
+    opts.optopt(
+        "",
+        "html",
+        "write an HTML curation report of the diff to this file",
+        "FILE",
+    );
+}
+
+/// Options for reading diffs.
+fn add_diff_input_options(opts: &mut Options) {
+    opts.optopt(
+        "f",
+        "format",
+        "format of the diff: json, or ndjson (default: ndjson if the file name contains .ndjson, json otherwise)",
+        "FORMAT",
+    );
+}
+
+fn add_output_option(opts: &mut Options) {
+    opts.optopt(
+        "o",
+        "output",
+        "write output to this file instead of STDOUT; compressed by extension (.gz for BGZF, .zst, .bz2, .xz)",
+        "FILE",
+    );
+}
+
+fn add_gff_format_option(opts: &mut Options) {
+    opts.optopt(
+        "",
+        "gff-format",
+        "format of written GFF data: gff3 (default), gtf, gff2",
+        "FORMAT",
+    );
+}
+
+fn get_options(command: &str) -> Options {
+    let mut opts = Options::new();
+    match command {
+        "diff" => {
+            add_compare_options(&mut opts);
+            add_diff_output_options(&mut opts);
+            add_output_option(&mut opts);
+        }
+        "apollo" => {
+            add_compare_options(&mut opts);
+            add_diff_output_options(&mut opts);
+            add_output_option(&mut opts);
+            opts.optopt(
+                "p",
+                "apollo-prefix",
+                "ID prefix for new elements (default: APOLLO_)",
+                "PREFIX",
+            );
+            opts.optopt(
+                "",
+                "reconciliation",
+                "write reconciliation table to this file (TSV if it ends in .tsv, otherwise JSON)",
+                "FILE",
+            );
+        }
+        "stats" => {
+            add_compare_options(&mut opts);
+            add_output_option(&mut opts);
+            opts.optflag(
+                "a",
+                "apollo",
+                "FILE2 is Apollo-style GFF, or Apollo JSON export (.json)",
+            );
+        }
+        "apply" => {
+            add_diff_input_options(&mut opts);
+            add_output_option(&mut opts);
+            add_gff_format_option(&mut opts);
+            opts.optflag(
+                "r",
+                "reverse",
+                "apply the diff backwards (BASE is the changed file)",
+            );
+            opts.optopt(
+                "",
+                "input-format",
+                "format of BASE: gff3, gtf, gff2 (default: detect)",
+                "FORMAT",
+            );
+        }
+        "validate" => add_diff_input_options(&mut opts),
+        "merge" => {
+            add_output_option(&mut opts);
+            add_gff_format_option(&mut opts);
+            opts.optopt(
+                "c",
+                "conflicts",
+                "write conflict report to this file (default: STDERR)",
+                "FILE",
+            );
+        }
+        "history" => add_output_option(&mut opts),
+        _ => {}
+    }
+    opts.optflag("h", "help", "print this help menu");
+    opts
+}
+
+/// Opens the output file, compressed by extension, or STDOUT. Call `finish` on it after writing.
+fn get_output(output_file: &Option<String>) -> Result<CompressedWriter, Box<dyn Error>> {
+    CompareGFF::get_file_writer(output_file.as_deref().unwrap_or("-"))
+}
+
+/// Returns the format of a diff file: `--format`, or `ndjson` if the file name contains `.ndjson`.
+fn get_diff_format(matches: &Matches, filename: &str) -> Result<String, Box<dyn Error>> {
+    match matches.opt_str("format") {
+        Some(format) => match format.as_str() {
+            "json" | "ndjson" => Ok(format),
+            _ => Err(From::from(format!(
+                "Unknown diff format '{}', use json or ndjson",
+                format
+            ))),
+        },
+        None => match filename.contains(".ndjson") {
+            true => Ok("ndjson".to_string()),
+            false => Ok("json".to_string()),
+        },
+    }
+}
+
+/// Reads a JSON or NDJSON diff file (compressed files are detected).
+fn read_diff(filename: &str, format: &str) -> Result<Value, Box<dyn Error>> {
+    let reader = io::BufReader::new(CompareGFF::get_file_reader(filename)?);
+    if format != "ndjson" {
+        return Ok(serde_json::from_reader(reader)?);
+    }
+    let mut changes: Vec<Value> = vec![];
+    for (line_number, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line) {
+            Ok(change) => changes.push(change),
+            Err(e) => return Err(From::from(format!("line {}: {}", line_number + 1, e))),
+        }
+    }
+    Ok(json!({ "changes": changes }))
+}
+
+/// Loads the two files to compare; for Apollo diffs, the second one can be an Apollo JSON export.
+fn load_files(matches: &Matches, apollo: bool) -> Result<CompareGFF, Box<dyn Error>> {
+    let files = &matches.free;
+    let mut cg = CompareGFF::new();
+    cg.record_issues(matches.opt_present("issues"));
+    cg.input_format(matches.opt_get::<GffFormat>("input-format")?);
+    cg.region(matches.opt_get::<Region>("region")?);
+    cg.load(GffSource::file(&files[0]), DataSet::Base)?;
+    let target = match apollo && files[1].ends_with(".json") {
+        true => GffSource::apollo_json(&files[1]),
+        false => GffSource::file(&files[1]),
+    };
+    cg.load(target, DataSet::Target)?;
+    Ok(cg)
+}
+
+/// Generates the diff, inverted for `--reverse`. Returns the diff, and the data set it applies to.
+fn get_diff(
+    cg: &CompareGFF,
+    matches: &Matches,
+    apollo: bool,
+) -> Result<(Value, DataSet), Box<dyn Error>> {
+    let diff = match apollo {
+        true => cg.diff_apollo()?,
+        false => cg.diff()?,
+    };
+    match matches.opt_present("reverse") {
+        true => Ok((CompareGFF::invert_diff(&diff)?, DataSet::Target)),
+        false => Ok((diff, DataSet::Base)),
+    }
+}
+
+fn write_reconciliation(diff: &Value, filename: &str) -> Result<(), Box<dyn Error>> {
+    let output = match filename.ends_with(".tsv") {
+        true => CompareGFF::apollo_reconciliation_tsv(diff)?,
+        false => format!("{:#}", diff["reconciliation"]),
+    };
+    fs::write(filename, output)?;
+    Ok(())
+}
+
+fn diff(matches: &Matches, apollo: bool) -> Result<(), Box<dyn Error>> {
+    let output_file = matches.opt_str("output");
+    let html_file = matches.opt_str("html");
+    let output_format = matches
+        .opt_str("format")
+        .unwrap_or_else(|| "json".to_string());
+    if !OUTPUT_FORMATS.contains(&output_format.as_str()) {
+        return Err(From::from(format!(
+            "Unknown output format '{}', use one of: {}",
+            output_format,
+            OUTPUT_FORMATS.join(", ")
+        )));
+    }
+    let mut cg = load_files(matches, apollo)?;
+    if apollo {
+        if let Some(prefix) = matches.opt_str("apollo-prefix") {
+            cg.apollo_id_prefix(prefix);
+        }
+    }
+
+    // Stream the changes as they are found, if nothing else needs the complete diff
+    if output_format == "ndjson"
+        && !apollo
+        && !matches.opt_present("reverse")
+        && !matches.opt_present("issues")
+        && html_file.is_none()
+    {
+        let mut output = get_output(&output_file)?;
+        cg.diff_ndjson(&mut output)?;
+        output.finish()?;
+        return Ok(());
+    }
+
+    let (diff, base) = get_diff(&cg, matches, apollo)?;
+    if apollo {
+        if let Some(filename) = matches.opt_str("reconciliation") {
+            write_reconciliation(&diff, &filename)?;
+        }
+    }
+    if let Some(filename) = html_file {
+        let html = cg.diff_html(&diff, base)?;
+        let mut output = get_output(&Some(filename))?;
+        write!(output, "{}", html)?;
+        output.finish()?;
+    }
+    let mut output = get_output(&output_file)?;
+    match output_format.as_str() {
+        "text" => {
+            let text = cg.diff_text(&diff, base, matches.opt_present("color"))?;
+            write!(output, "{}", text)?
+        }
+        "bed" => write!(output, "{}", cg.diff_bed(&diff, base)?)?,
+        "tsv" => write!(output, "{}", cg.diff_table(&diff, base, b'\t')?)?,
+        "csv" => write!(output, "{}", cg.diff_table(&diff, base, b',')?)?,
+        "gff" => cg.write_diff_gff(&mut output, &diff, base)?,
+        "ndjson" => {
+            for change in diff["changes"].as_array().into_iter().flatten() {
+                writeln!(output, "{}", change)?;
+            }
+            // Not a change, so not part of the NDJSON diff
+            for issue in diff["issues"].as_array().into_iter().flatten() {
+                eprintln!("{}", issue);
+            }
+        }
+        _ => writeln!(output, "{:#}", diff)?,
+    }
+    output.finish()?;
+    Ok(())
+}
+
+fn apply(matches: &Matches) -> Result<(), Box<dyn Error>> {
+    let files = &matches.free;
+    let gff_format = matches.opt_get_default("gff-format", GffFormat::Gff3)?;
+    let diff_format = get_diff_format(matches, &files[1])?;
+    let reverse = matches.opt_present("reverse");
+    let mut cg = CompareGFF::new();
+    cg.input_format(matches.opt_get::<GffFormat>("input-format")?);
+    cg.load(GffSource::file(&files[0]), DataSet::Base)?;
+    if diff_format == "ndjson" && !reverse {
+        // Apply the changes while reading them
+        let reader = io::BufReader::new(CompareGFF::get_file_reader(&files[1])?);
+        cg.apply_diff_ndjson(reader)?;
+    } else {
+        let diff = read_diff(&files[1], &diff_format)?;
+        let diff = match reverse {
+            true => {
+                CompareGFF::validate_diff(&diff)?;
+                CompareGFF::invert_diff(&diff)?
+            }
+            false => diff,
+        };
+        cg.apply_diff(&diff)?;
+    }
+    let mut output = get_output(&matches.opt_str("output"))?;
+    cg.write_data1_as(&mut output, gff_format)?;
+    output.finish()?;
+    Ok(())
+}
+
+fn stats(matches: &Matches) -> Result<(), Box<dyn Error>> {
+    let apollo = matches.opt_present("apollo");
+    let cg = load_files(matches, apollo)?;
+    let (diff, base) = get_diff(&cg, matches, apollo)?;
+    let stats = cg.diff_stats(&diff, base)?;
+    let mut output = get_output(&matches.opt_str("output"))?;
+    writeln!(output, "{:#}", stats)?;
+    output.finish()?;
+    Ok(())
+}
+
+/// Checks all given diff files, and fails if any of them is not valid.
+fn validate(matches: &Matches) -> Result<(), Box<dyn Error>> {
+    let mut invalid: Vec<&str> = vec![];
+    for filename in &matches.free {
+        let result = get_diff_format(matches, filename)
+            .and_then(|format| read_diff(filename, &format))
+            .and_then(|diff| CompareGFF::validate_diff(&diff));
+        match result {
+            Ok(_) => println!("{}: OK", filename),
+            Err(e) => {
+                println!("{}: {}", filename, e);
+                invalid.push(filename);
+            }
+        }
+    }
+    match invalid.is_empty() {
+        true => Ok(()),
+        false => Err(From::from(format!("Invalid diffs: {}", invalid.join(", ")))),
+    }
+}
+
+fn merge(matches: &Matches) -> Result<(), Box<dyn Error>> {
+    let files = &matches.free;
+    let gff_format = matches.opt_get_default("gff-format", GffFormat::Gff3)?;
+    let (cg, merged) = CompareGFF::merge_files(&files[0], &files[1], &files[2])?;
+    let mut output = get_output(&matches.opt_str("output"))?;
+    cg.write_data1_as(&mut output, gff_format)?;
+    output.finish()?;
+    let mut report = json!({"conflicts":merged["conflicts"]});
+    if !merged["issues"].is_null() {
+        report["issues"] = merged["issues"].to_owned();
+    }
+    match matches.opt_str("conflicts") {
+        Some(filename) => fs::write(filename, format!("{:#}", report))?,
+        None => eprintln!("{:#}", report),
+    }
+    Ok(())
+}
+
+fn history(matches: &Matches) -> Result<(), Box<dyn Error>> {
+    let cg = CompareGFF::new_from_versions(matches.free.to_vec())?;
+    let history = cg.history()?;
+    let mut output = get_output(&matches.opt_str("output"))?;
+    writeln!(output, "{:#}", history)?;
+    output.finish()?;
+    Ok(())
 }
 
 fn main() {
     let args: Vec<String> = env::args().collect();
     let program = args[0].clone();
 
-    let mut opts = Options::new();
-    opts.optflag("a", "apollo", "Second file is Apollo-style GFF");
-    opts.optflag("d", "diff", "output diff");
-    opts.optflag("x", "apply", "apply diff");
-    opts.optflag("i", "issues", "record issues");
-    opts.optflag("h", "help", "print this help menu");
-    let matches = match opts.parse(&args[1..]) {
+    let name = args.get(1).map(|arg| arg.as_str());
+    let command = match COMMANDS.iter().find(|command| name == Some(command.name)) {
+        Some(command) => command,
+        None => {
+            match name {
+                None | Some("-h") | Some("--help") | Some("help") => {
+                    println!("{}", get_usage(&program))
+                }
+                Some(arg) => {
+                    eprintln!("Unknown command '{}'\n\n{}", arg, get_usage(&program));
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+    };
+    let opts = get_options(command.name);
+    let matches = match opts.parse(&args[2..]) {
         Ok(m) => m,
-        Err(f) => panic!(f.to_string()),
+        Err(f) => {
+            eprintln!("{}\n\n{}", f, get_command_usage(&program, command, &opts));
+            std::process::exit(1);
+        }
     };
     if matches.opt_present("h") {
-        println!("{}", get_usage(&program, opts));
+        println!("{}", get_command_usage(&program, command, &opts));
         return;
     }
-    let do_diff = matches.opt_present("d");
-    let do_record_issues = matches.opt_present("i");
-    let do_apply = matches.opt_present("x");
-    let apollo = matches.opt_present("a");
-    let files: Vec<String> = matches.free;
-
-    if files.len() != 2 {
-        println!("{}", get_usage(&program, opts));
-        return;
+    if matches.free.len() < command.min_files || matches.free.len() > command.max_files {
+        eprintln!("{}", get_command_usage(&program, command, &opts));
+        std::process::exit(1);
     }
 
-    let mut cg = CompareGFF::new(); //_from_files(&files[0], &files[1]).unwrap();
-    cg.record_issues(do_record_issues);
-    cg.load_gff(&files[0], 1).unwrap();
-    cg.load_gff(&files[1], 2).unwrap();
-    let diff = match apollo {
-        true => cg.diff_apollo(),
-        false => cg.diff(),
+    let result = match command.name {
+        "diff" => diff(&matches, false),
+        "apollo" => diff(&matches, true),
+        "apply" => apply(&matches),
+        "stats" => stats(&matches),
+        "validate" => validate(&matches),
+        "merge" => merge(&matches),
+        _ => history(&matches),
     };
-    match diff {
-        Ok(diff) => match (do_diff, do_apply) {
-            (true, false) | (false, false) => {
-                println!("{:#}", diff);
-            }
-            (false, true) => {
-                match cg.apply_diff(&diff) {
-                    Ok(_) => {
-                        cg.write_data1(Box::new(io::stdout())).unwrap();
-                    }
-                    Err(e) => {
-                        println!("{}", e);
-                    }
-                };
-            }
-            (true, true) => println!("--diff and --apply are both set, abort"),
-        },
-        Err(e) => println!("{}", e),
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
     }
 }
@@ -0,0 +1,270 @@
+use crate::merge::MergeSlot;
+use crate::{CompareGFF, HashGFF, DIFF_FORMAT_VERSION};
+use serde_json::value::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+
+impl CompareGFF {
+    /// Returns the row add/remove change from a list of changes for a single ID, if any.
+    fn compose_row_change<'a>(changes: &[&'a Value]) -> Option<&'a Value> {
+        changes
+            .iter()
+            .filter(|change| change["what"] == "row")
+            .filter(|change| change["action"] == "add" || change["action"] == "remove")
+            .copied()
+            .nth(0)
+    }
+
+    /// Applies changes to a single GFF element, returns the resulting element (`None` if it was removed).
+    fn compose_apply(
+        id: &str,
+        element: &Value,
+        changes: &[&Value],
+    ) -> Result<Option<bio::io::gff::Record>, Box<dyn Error>> {
+        let mut data: HashGFF = HashGFF::new();
+        data.insert(id.to_string(), Self::gff_from_json(element)?);
+        let changes: Vec<Value> = changes.iter().map(|change| (*change).to_owned()).collect();
+        Self::apply_changes(&changes, &mut data)?;
+        Ok(data.remove(id))
+    }
+
+    /// Composes the row updates and attribute changes of a single ID, if neither diff adds or removes that row.
+    fn compose_updates(first: &[&Value], second: &[&Value]) -> Result<Vec<Value>, String> {
+        let mut updates: BTreeMap<String, Value> = BTreeMap::new();
+        let mut attributes: BTreeMap<(String, String), Value> = BTreeMap::new();
+        let mut other: Vec<Value> = vec![];
+        for change in first.iter().chain(second.iter()) {
+            let key = change["key"].as_str().unwrap_or("").to_string();
+            match (change["what"].as_str(), change["action"].as_str()) {
+                (Some("row"), Some("update")) => match updates.get_mut(&key) {
+                    Some(update) => {
+                        if update["value"] != change["old_value"] {
+                            return Err(format!(
+                                "compose_updates: {} does not follow up on {}",
+                                change, update
+                            ));
+                        }
+                        update["value"] = change["value"].to_owned();
+                    }
+                    None => {
+                        updates.insert(key, (*change).to_owned());
+                    }
+                },
+                (Some("attribute"), Some(action)) => {
+                    let value = change["value"].as_str().unwrap_or("").to_string();
+                    match attributes.remove(&(key.to_owned(), value.to_owned())) {
+                        Some(previous) => {
+                            if previous["action"] == action {
+                                return Err(format!(
+                                    "compose_updates: {} does not follow up on {}",
+                                    change, previous
+                                ));
+                            }
+                            // add+remove or remove+add cancel each other out
+                        }
+                        None => {
+                            attributes.insert((key, value), (*change).to_owned());
+                        }
+                    }
+                }
+                _ => other.push((*change).to_owned()),
+            }
+        }
+        Ok(updates
+            .into_values()
+            .filter(|update| update["value"] != update["old_value"])
+            .chain(attributes.into_values())
+            .chain(other)
+            .collect())
+    }
+
+    /// Composes the changes of a single ID.
+    fn compose_id(
+        id: &String,
+        first: &[&Value],
+        second: &[&Value],
+    ) -> Result<Vec<Value>, Box<dyn Error>> {
+        if first.is_empty() || second.is_empty() {
+            return Ok(first
+                .iter()
+                .chain(second.iter())
+                .map(|change| (*change).to_owned())
+                .collect());
+        }
+        let row1 = Self::compose_row_change(first);
+        let row2 = Self::compose_row_change(second);
+        let action1 = row1.and_then(|change| change["action"].as_str());
+        let action2 = row2.and_then(|change| change["action"].as_str());
+        match (action1, action2) {
+            (Some("add"), _) => {
+                // Added in the first diff, changed in the second one
+                let added = &row1.unwrap()["added_element"];
+                Ok(match Self::compose_apply(id, added, second)? {
+                    Some(element) => vec![
+                        json!({"what":"row","action":"add","id":id,"added_element":Self::gff_to_json(&element)}),
+                    ],
+                    None => vec![],
+                })
+            }
+            (Some("remove"), Some("add")) => {
+                // Removed in the first diff, re-added in the second one
+                let r1 = Self::gff_from_json(&row1.unwrap()["removed_element"])?;
+                let r2 = Self::gff_from_json(&row2.unwrap()["added_element"])?;
+                Ok(Self::compare_records(id, &r1, &r2))
+            }
+            (None, Some("remove")) => {
+                // Changed in the first diff, removed in the second one; remove the original element
+                let inverted = first
+                    .iter()
+                    .map(|change| Self::invert_change(change))
+                    .collect::<Result<Vec<Value>, String>>()?;
+                let inverted: Vec<&Value> = inverted.iter().collect();
+                let removed = &row2.unwrap()["removed_element"];
+                match Self::compose_apply(id, removed, &inverted)? {
+                    Some(element) => Ok(vec![
+                        json!({"what":"row","action":"remove","id":id,"removed_element":Self::gff_to_json(&element)}),
+                    ]),
+                    None => Err(From::from(format!("compose_id: Could not restore {}", id))),
+                }
+            }
+            (None, None) => Ok(Self::compose_updates(first, second)?),
+            (row1, row2) => Err(From::from(format!(
+                "compose_id: Row {} cannot be {} in the first diff, then {} in the second one",
+                id,
+                row1.unwrap_or("changed"),
+                row2.unwrap_or("changed")
+            ))),
+        }
+    }
+
+    /// Composes two consecutive diffs (A to B, and B to C) into a single diff (A to C).
+    /// Changes to an ID that cannot be composed (e.g. changing a row that was removed by the first diff)
+    /// are not in `changes`, but reported in `conflicts`.
+    pub fn compose_diffs(first: &Value, second: &Value) -> Result<Value, Box<dyn Error>> {
+        let (first, second) = match (first["changes"].as_array(), second["changes"].as_array()) {
+            (Some(first), Some(second)) => (first, second),
+            _ => return Err(From::from("compose_diffs: No changes in diff")),
+        };
+        let mut ids: BTreeMap<String, (Vec<&Value>, Vec<&Value>)> = BTreeMap::new();
+        first.iter().for_each(|change| {
+            let id = change["id"].as_str().unwrap_or("").to_string();
+            ids.entry(id).or_default().0.push(change)
+        });
+        second.iter().for_each(|change| {
+            let id = change["id"].as_str().unwrap_or("").to_string();
+            ids.entry(id).or_default().1.push(change)
+        });
+
+        let mut changes: Vec<Value> = vec![];
+        let mut conflicts: Vec<Value> = vec![];
+        for (id, (first, second)) in ids {
+            match Self::compose_id(&id, &first, &second) {
+                Ok(mut id_changes) => changes.append(&mut id_changes),
+                Err(e) => conflicts
+                    .push(json!({"id":id,"reason":e.to_string(),"first":first,"second":second})),
+            }
+        }
+        Ok(
+            json!({"format_version": DIFF_FORMAT_VERSION, "changes": changes, "conflicts": conflicts}),
+        )
+    }
+
+    /// Rebases a diff made against an old base onto a new base.
+    /// `base_change` is the diff from the old base to the new base.
+    /// Changes in `diff` that are already in `base_change` are dropped.
+    /// Changes that conflict with `base_change` are reported in `conflicts`,
+    /// with the `base_change` side as `ours` and the `diff` side as `theirs`.
+    pub fn rebase_diff(diff: &Value, base_change: &Value) -> Result<Value, Box<dyn Error>> {
+        let mut merged = Self::merge_diffs(base_change, diff)?;
+        let base_changes = match base_change["changes"].as_array() {
+            Some(changes) => changes,
+            None => return Err(From::from("rebase_diff: No changes in diff")),
+        };
+        let mut base_slots: HashMap<MergeSlot, Vec<&Value>> = HashMap::new();
+        for change in base_changes {
+            base_slots
+                .entry(Self::merge_slot(change))
+                .or_default()
+                .push(change);
+        }
+        let in_base = |change: &Value| {
+            base_slots
+                .get(&Self::merge_slot(change))
+                .is_some_and(|slot| slot.contains(&change))
+        };
+        let changes: Vec<Value> = match merged["changes"].as_array() {
+            Some(changes) => changes
+                .iter()
+                .filter(|change| !in_base(change))
+                .cloned()
+                .collect(),
+            None => vec![],
+        };
+        merged["changes"] = json!(changes);
+        Ok(merged)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DataSet, GffSource};
+
+    fn diff_files(file1: &str, file2: &str) -> Value {
+        let mut cg = CompareGFF::new();
+        cg.record_issues(true);
+        cg.load_test_files("merge_three_way", file1, file2);
+        cg.diff().unwrap()
+    }
+
+    #[test]
+    fn compose_diffs() {
+        let first = diff_files("base.gff", "ours.gff");
+        let second = diff_files("ours.gff", "theirs.gff");
+        let composed = CompareGFF::compose_diffs(&first, &second).unwrap();
+        assert_eq!(composed["conflicts"], json!([]));
+
+        // Composed diff turns base into theirs
+        let mut cg = CompareGFF::new();
+        cg.load_test_files("merge_three_way", "base.gff", "theirs.gff");
+        cg.apply_diff(&composed).unwrap();
+        let diff = cg.diff().unwrap();
+        assert_eq!(diff["changes"].as_array().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn compose_diffs_conflict() {
+        let first = json!({"changes":[{"action":"update","what":"row","id":"E1","key":"end","value":"1200","old_value":"900"}]});
+        let second = json!({"changes":[{"action":"update","what":"row","id":"E1","key":"end","value":"1300","old_value":"1000"}]});
+        let composed = CompareGFF::compose_diffs(&first, &second).unwrap();
+        assert_eq!(composed["changes"], json!([]));
+        assert_eq!(composed["conflicts"][0]["id"], json!("E1"));
+    }
+
+    #[test]
+    fn rebase_diff() {
+        let base_change = diff_files("base.gff", "ours.gff");
+        let diff = diff_files("base.gff", "theirs.gff");
+        let rebased = CompareGFF::rebase_diff(&diff, &base_change).unwrap();
+        let conflicts: Vec<&str> = rebased["conflicts"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(|conflict| conflict["id"].as_str())
+            .collect();
+        assert_eq!(conflicts, vec!["C0004", "G0001"]);
+
+        // Rebased diff applies cleanly to the new base
+        let mut cg = CompareGFF::new();
+        cg.load(
+            GffSource::file("test/merge_three_way/ours.gff"),
+            DataSet::Base,
+        )
+        .unwrap();
+        cg.apply_diff(&rebased).unwrap();
+        let data1 = cg.data1.as_ref().unwrap();
+        assert_eq!(*data1["E0001"].start(), 10);
+        assert_eq!(*data1["E0005"].end(), 1200);
+        assert!(!data1.contains_key("E0003"));
+    }
+}
@@ -0,0 +1,406 @@
+use crate::CompareGFF;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+
+/// Maximum uncompressed size of a BGZF block, as used by bgzip.
+const BGZF_BLOCK_SIZE: usize = 0xff00;
+
+/// Maximum compressed size of a BGZF block, as its size is stored in 16 bits.
+const BGZF_MAX_BLOCK_SIZE: usize = 0x10000;
+
+/// The empty block that marks the end of a BGZF file.
+const BGZF_EOF: [u8; 28] = [
+    0x1f, 0x8b, 8, 4, 0, 0, 0, 0, 0, 0xff, 6, 0, b'B', b'C', 2, 0, 0x1b, 0, 3, 0, 0, 0, 0, 0, 0, 0,
+    0, 0,
+];
+
+/// Compression formats of files, detected by their magic bytes (input) or chosen by their extension (output).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    None,
+    /// gzip, including multi-member gzip.
+    Gzip,
+    /// Blocked gzip (bgzip), as used for tabix-indexed files; readable by any gzip decompressor.
+    Bgzf,
+    Bzip2,
+    Xz,
+    Zstd,
+}
+
+impl Compression {
+    /// Detects the compression format from the first bytes of a file.
+    pub fn detect(head: &[u8]) -> Self {
+        if head.starts_with(&[0x1f, 0x8b, 8, 4]) && head.get(12..14) == Some(b"BC") {
+            Compression::Bgzf
+        } else if head.starts_with(&[0x1f, 0x8b]) {
+            Compression::Gzip
+        } else if head.starts_with(b"BZh") {
+            Compression::Bzip2
+        } else if head.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Compression::Xz
+        } else if head.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+
+    /// Chooses the compression of an output file by its extension.
+    /// `.gz` and `.bgz` are BGZF, so they can be tabix-indexed; `.gzip` is plain gzip.
+    pub fn from_filename(filename: &str) -> Self {
+        match filename.rsplit('.').next() {
+            Some("gz") | Some("bgz") => Compression::Bgzf,
+            Some("gzip") => Compression::Gzip,
+            Some("bz2") => Compression::Bzip2,
+            Some("xz") => Compression::Xz,
+            Some("zst") | Some("zstd") => Compression::Zstd,
+            _ => Compression::None,
+        }
+    }
+}
+
+/// Writes BGZF blocks. The last block and the end-of-file marker are written by `finish`.
+pub struct BgzfWriter<W: Write> {
+    inner: W,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> BgzfWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            buffer: Vec::with_capacity(BGZF_BLOCK_SIZE),
+        }
+    }
+
+    /// Compresses the buffered data into a block. Data that does not fit into a block when compressed is
+    /// stored uncompressed instead, like bgzip does.
+    fn write_block(&mut self) -> std::io::Result<()> {
+        let mut block = Self::encode_block(&self.buffer, false)?;
+        if block.len() > BGZF_MAX_BLOCK_SIZE {
+            block = Self::encode_block(&self.buffer, true)?;
+        }
+        let block_size = u16::try_from(block.len() - 1)
+            .map_err(|_| std::io::Error::other("BGZF block too large"))?;
+        block[16..18].copy_from_slice(&block_size.to_le_bytes());
+        self.inner.write_all(&block)?;
+        self.buffer.clear();
+        Ok(())
+    }
+
+    /// Encodes data as a gzip member with an empty BGZF block size field.
+    fn encode_block(data: &[u8], store: bool) -> std::io::Result<Vec<u8>> {
+        let header = libflate::gzip::HeaderBuilder::new()
+            .modification_time(0)
+            .extra_field(libflate::gzip::ExtraField {
+                subfields: vec![libflate::gzip::ExtraSubField {
+                    id: [b'B', b'C'],
+                    data: vec![0, 0], // Block size, set below
+                }],
+            })
+            .finish();
+        let options = libflate::gzip::EncodeOptions::new().header(header);
+        let options = match store {
+            true => options.no_compression(),
+            false => options,
+        };
+        let mut encoder = libflate::gzip::Encoder::with_options(vec![], options)?;
+        encoder.write_all(data)?;
+        encoder.finish().into_result()
+    }
+
+    /// Writes the remaining data and the end-of-file marker, and returns the inner writer.
+    pub fn finish(mut self) -> std::io::Result<W> {
+        if !self.buffer.is_empty() {
+            self.write_block()?;
+        }
+        self.inner.write_all(&BGZF_EOF)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for BgzfWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = buf.len().min(BGZF_BLOCK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+        if self.buffer.len() >= BGZF_BLOCK_SIZE {
+            self.write_block()?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A writer for output files, compressed or not. `finish` has to be called at the end: it writes the end of
+/// the compressed data and flushes, and reports errors that would otherwise go unnoticed.
+pub enum CompressedWriter {
+    None(Box<dyn Write>),
+    Gzip(libflate::gzip::Encoder<Box<dyn Write>>),
+    Bgzf(BgzfWriter<Box<dyn Write>>),
+    Bzip2(bzip2::write::BzEncoder<Box<dyn Write>>),
+    Xz(xz2::write::XzEncoder<Box<dyn Write>>),
+    Zstd(zstd::stream::write::Encoder<'static, Box<dyn Write>>),
+}
+
+impl CompressedWriter {
+    /// Writes the end of the compressed data, and flushes the underlying writer.
+    pub fn finish(self) -> std::io::Result<()> {
+        let mut inner = match self {
+            CompressedWriter::None(writer) => writer,
+            CompressedWriter::Gzip(writer) => writer.finish().into_result()?,
+            CompressedWriter::Bgzf(writer) => writer.finish()?,
+            CompressedWriter::Bzip2(writer) => writer.finish()?,
+            CompressedWriter::Xz(writer) => writer.finish()?,
+            CompressedWriter::Zstd(writer) => writer.finish()?,
+        };
+        inner.flush()
+    }
+
+    fn writer(&mut self) -> &mut dyn Write {
+        match self {
+            CompressedWriter::None(writer) => writer,
+            CompressedWriter::Gzip(writer) => writer,
+            CompressedWriter::Bgzf(writer) => writer,
+            CompressedWriter::Bzip2(writer) => writer,
+            CompressedWriter::Xz(writer) => writer,
+            CompressedWriter::Zstd(writer) => writer,
+        }
+    }
+}
+
+impl Write for CompressedWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.writer().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer().flush()
+    }
+}
+
+impl CompareGFF {
+    /// Wraps a reader in a decompressor, if the content is compressed.
+    pub fn decompress(reader: Box<dyn Read>) -> Result<Box<dyn Read>, Box<dyn Error>> {
+        let mut reader = BufReader::new(reader);
+        Ok(match Compression::detect(reader.fill_buf()?) {
+            Compression::None => Box::new(reader),
+            Compression::Gzip | Compression::Bgzf => {
+                Box::new(libflate::gzip::MultiDecoder::new(reader)?)
+            }
+            Compression::Bzip2 => Box::new(bzip2::read::MultiBzDecoder::new(reader)),
+            Compression::Xz => Box::new(xz2::read::XzDecoder::new_multi_decoder(reader)),
+            Compression::Zstd => Box::new(zstd::stream::read::Decoder::with_buffer(reader)?),
+        })
+    }
+
+    /// Opens a file for reading, decompressing it if necessary. `-` reads from STDIN.
+    pub fn get_file_reader<S: Into<String>>(filename: S) -> Result<Box<dyn Read>, Box<dyn Error>> {
+        let filename: String = filename.into();
+        let reader: Box<dyn Read> = match filename.as_str() {
+            "-" => Box::new(std::io::stdin()),
+            _ => Box::new(File::open(filename)?),
+        };
+        Self::decompress(reader)
+    }
+
+    /// Wraps a writer in a compressor. The compressed data is completed by `CompressedWriter::finish`.
+    pub fn compress(
+        writer: Box<dyn Write>,
+        compression: Compression,
+    ) -> Result<CompressedWriter, Box<dyn Error>> {
+        Ok(match compression {
+            Compression::None => CompressedWriter::None(writer),
+            Compression::Gzip => CompressedWriter::Gzip(libflate::gzip::Encoder::new(writer)?),
+            Compression::Bgzf => CompressedWriter::Bgzf(BgzfWriter::new(writer)),
+            Compression::Bzip2 => CompressedWriter::Bzip2(bzip2::write::BzEncoder::new(
+                writer,
+                bzip2::Compression::default(),
+            )),
+            Compression::Xz => CompressedWriter::Xz(xz2::write::XzEncoder::new(writer, 6)),
+            Compression::Zstd => {
+                CompressedWriter::Zstd(zstd::stream::write::Encoder::new(writer, 0)?)
+            }
+        })
+    }
+
+    /// Creates a file for writing, compressed according to its extension (see `Compression::from_filename`).
+    /// `-` writes to STDOUT, uncompressed. `CompressedWriter::finish` has to be called after writing.
+    pub fn get_file_writer<S: Into<String>>(
+        filename: S,
+    ) -> Result<CompressedWriter, Box<dyn Error>> {
+        let filename: String = filename.into();
+        if filename == "-" {
+            return Ok(CompressedWriter::None(Box::new(std::io::stdout())));
+        }
+        let compression = Compression::from_filename(&filename);
+        let file = std::io::BufWriter::new(File::create(filename)?);
+        Self::compress(Box::new(file), compression)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::{Cursor, Write};
+
+    const GFF: &str = "test/no_change/core.gff";
+
+    fn decompressed(data: Vec<u8>) -> String {
+        let mut ret = String::new();
+        CompareGFF::decompress(Box::new(Cursor::new(data)))
+            .unwrap()
+            .read_to_string(&mut ret)
+            .unwrap();
+        ret
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = libflate::gzip::Encoder::new(vec![]).unwrap();
+        encoder.write_all(data).unwrap();
+        encoder.finish().into_result().unwrap()
+    }
+
+    #[test]
+    fn decompress_gzip_multi_member() {
+        let text = fs::read_to_string(GFF).unwrap();
+        let (part1, part2) = text.split_at(text.len() / 2);
+        let mut data = gzip(part1.as_bytes());
+        data.append(&mut gzip(part2.as_bytes()));
+        assert_eq!(Compression::detect(&data), Compression::Gzip);
+        assert_eq!(decompressed(data), text);
+    }
+
+    #[test]
+    fn decompress_bzip2_xz_zstd() {
+        let text = fs::read_to_string(GFF).unwrap();
+
+        let mut encoder = bzip2::write::BzEncoder::new(vec![], bzip2::Compression::default());
+        encoder.write_all(text.as_bytes()).unwrap();
+        let data = encoder.finish().unwrap();
+        assert_eq!(Compression::detect(&data), Compression::Bzip2);
+        assert_eq!(decompressed(data), text);
+
+        let mut encoder = xz2::write::XzEncoder::new(vec![], 6);
+        encoder.write_all(text.as_bytes()).unwrap();
+        let data = encoder.finish().unwrap();
+        assert_eq!(Compression::detect(&data), Compression::Xz);
+        assert_eq!(decompressed(data), text);
+
+        let data = zstd::stream::encode_all(text.as_bytes(), 0).unwrap();
+        assert_eq!(Compression::detect(&data), Compression::Zstd);
+        assert_eq!(decompressed(data), text);
+
+        assert_eq!(decompressed(text.as_bytes().to_vec()), text);
+    }
+
+    #[test]
+    fn bgzf_writer() {
+        let text = fs::read_to_string(GFF).unwrap().repeat(100);
+        let mut writer = BgzfWriter::new(vec![]);
+        writer.write_all(text.as_bytes()).unwrap();
+        let data = writer.finish().unwrap();
+        assert_eq!(Compression::detect(&data), Compression::Bgzf);
+        assert!(data.ends_with(&BGZF_EOF));
+        assert!(data.len() < text.len());
+        assert_eq!(decompressed(data), text);
+    }
+
+    #[test]
+    fn bgzf_writer_incompressible() {
+        // Pseudo-random bytes do not compress; block sizes have to fit into 16 bits anyway
+        let mut state = 1u32;
+        let data: Vec<u8> = (0..200000)
+            .map(|_| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect();
+        let mut writer = BgzfWriter::new(vec![]);
+        writer.write_all(&data).unwrap();
+        let compressed = writer.finish().unwrap();
+        let mut pos = 0;
+        while pos < compressed.len() {
+            let block_size = u16::from_le_bytes([compressed[pos + 16], compressed[pos + 17]]);
+            pos += block_size as usize + 1;
+        }
+        assert_eq!(pos, compressed.len());
+        let mut ret = vec![];
+        CompareGFF::decompress(Box::new(Cursor::new(compressed)))
+            .unwrap()
+            .read_to_end(&mut ret)
+            .unwrap();
+        assert_eq!(ret, data);
+
+        // The fallback for blocks that grow too much when compressed
+        let stored = BgzfWriter::<Vec<u8>>::encode_block(&data[..BGZF_BLOCK_SIZE], true).unwrap();
+        assert!(stored.len() <= BGZF_MAX_BLOCK_SIZE);
+    }
+
+    /// A writer that fails like a full disk.
+    struct FullDisk;
+
+    impl Write for FullDisk {
+        fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::other("disk full"))
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn output_compression_errors() {
+        for compression in &[
+            Compression::None,
+            Compression::Gzip,
+            Compression::Bgzf,
+            Compression::Bzip2,
+            Compression::Xz,
+            Compression::Zstd,
+        ] {
+            let result =
+                CompareGFF::compress(Box::new(FullDisk), *compression).and_then(|mut writer| {
+                    writer.write_all(b"chr1")?;
+                    Ok(writer.finish()?)
+                });
+            assert!(result.is_err(), "{:?}", compression);
+        }
+    }
+
+    #[test]
+    fn output_compression() {
+        assert_eq!(Compression::from_filename("out.gff.gz"), Compression::Bgzf);
+        assert_eq!(
+            Compression::from_filename("diff.json.zst"),
+            Compression::Zstd
+        );
+        assert_eq!(Compression::from_filename("diff.json"), Compression::None);
+        let text = fs::read_to_string(GFF).unwrap();
+        for compression in &[
+            Compression::Gzip,
+            Compression::Bgzf,
+            Compression::Bzip2,
+            Compression::Xz,
+            Compression::Zstd,
+        ] {
+            let filename = std::env::temp_dir().join(format!("gff_diff_test_{:?}", compression));
+            let filename = filename.to_str().unwrap();
+            let file = Box::new(File::create(filename).unwrap());
+            let mut writer = CompareGFF::compress(file, *compression).unwrap();
+            writer.write_all(text.as_bytes()).unwrap();
+            writer.finish().unwrap();
+            let data = fs::read(filename).unwrap();
+            fs::remove_file(filename).unwrap();
+            assert_eq!(Compression::detect(&data), *compression);
+            assert_eq!(decompressed(data), text);
+        }
+    }
+}
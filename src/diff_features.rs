@@ -0,0 +1,242 @@
+use crate::{CompareGFF, DataSet, HashGFF};
+use bio::io::gff;
+use serde_json::value::Value;
+use std::collections::HashMap;
+use std::error::Error;
+
+/// Names of the GFF columns, as used for the `key` of row updates in diffs.
+pub(crate) const COLUMN_NAMES: [&str; 8] = [
+    "seqname",
+    "source",
+    "feature_type",
+    "start",
+    "end",
+    "score",
+    "strand",
+    "frame",
+];
+
+/// Renders a JSON value of a change as text.
+pub(crate) fn value_text(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.to_owned(),
+        v => v.to_string(),
+    }
+}
+
+/// Returns the old and the new value of a change as text; empty if there is none.
+pub(crate) fn change_values(change: &Value) -> (String, String) {
+    match (change["what"].as_str(), change["action"].as_str()) {
+        (Some("row"), Some("update")) => (
+            value_text(&change["old_value"]),
+            value_text(&change["value"]),
+        ),
+        (Some("attribute"), Some("remove")) => (value_text(&change["value"]), String::new()),
+        (Some("attribute"), _) => (String::new(), value_text(&change["value"])),
+        _ => (String::new(), String::new()),
+    }
+}
+
+/// Groups the changes of a diff by feature ID, in order of appearance.
+pub(crate) fn changes_by_id(diff: &Value) -> HashMap<&str, Vec<&Value>> {
+    let mut ret: HashMap<&str, Vec<&Value>> = HashMap::new();
+    for change in diff["changes"].as_array().into_iter().flatten() {
+        if let Some(id) = change["id"].as_str() {
+            ret.entry(id).or_default().push(change);
+        }
+    }
+    ret
+}
+
+/// A feature affected by a diff, before and after the changes.
+#[derive(Clone, Debug)]
+pub(crate) struct ChangedFeature {
+    pub id: String,
+    /// The feature before the diff; `None` if it was added.
+    pub old: Option<gff::Record>,
+    /// The feature after the diff; `None` if it was removed.
+    pub new: Option<gff::Record>,
+}
+
+impl ChangedFeature {
+    /// Indexes changed features by ID.
+    pub fn by_id(features: &[ChangedFeature]) -> HashMap<&str, &ChangedFeature> {
+        features
+            .iter()
+            .map(|feature| (feature.id.as_str(), feature))
+            .collect()
+    }
+
+    /// Returns `added`, `removed`, or `modified`.
+    pub fn status(&self) -> &str {
+        match (&self.old, &self.new) {
+            (None, _) => "added",
+            (_, None) => "removed",
+            _ => "modified",
+        }
+    }
+
+    /// Returns the new feature, or the old one if it was removed.
+    pub fn element(&self) -> &gff::Record {
+        self.new.as_ref().or(self.old.as_ref()).unwrap()
+    }
+
+    /// Returns the names of the columns that differ between the old and the new feature.
+    pub fn changed_columns(&self) -> Vec<&'static str> {
+        let (old, new) = match (&self.old, &self.new) {
+            (Some(old), Some(new)) => (old, new),
+            _ => return vec![],
+        };
+        let columns = |e: &gff::Record| {
+            vec![
+                e.seqname().to_string(),
+                e.source().to_string(),
+                e.feature_type().to_string(),
+                e.start().to_string(),
+                e.end().to_string(),
+                CompareGFF::score_text(e),
+                CompareGFF::strand_symbol(e),
+                e.frame().to_string(),
+            ]
+        };
+        COLUMN_NAMES
+            .iter()
+            .zip(columns(old).iter().zip(columns(new).iter()))
+            .filter(|(_, (v1, v2))| v1 != v2)
+            .map(|(name, _)| *name)
+            .collect()
+    }
+
+    /// Returns the (sorted) keys of the attributes that differ between the old and the new feature.
+    pub fn changed_attributes(&self) -> Vec<String> {
+        let (old, new) = match (&self.old, &self.new) {
+            (Some(old), Some(new)) => (old.attributes(), new.attributes()),
+            _ => return vec![],
+        };
+        let mut ret: Vec<String> = old
+            .keys()
+            .chain(new.keys())
+            .filter(|key| old.get_vec(*key) != new.get_vec(*key))
+            .cloned()
+            .collect();
+        ret.sort();
+        ret.dedup();
+        ret
+    }
+}
+
+impl CompareGFF {
+    /// Returns a data set, or an error if it is not loaded.
+    pub(crate) fn loaded_data(&self, data_set: DataSet) -> Result<&HashGFF, Box<dyn Error>> {
+        let data = match data_set {
+            DataSet::Base => &self.data1,
+            DataSet::Target => &self.data2,
+        };
+        match data {
+            Some(data) => Ok(data),
+            None => Err(From::from(format!("{:?} data set is not loaded", data_set))),
+        }
+    }
+
+    /// Applies a diff to each affected feature of a data set, and returns them before and after the changes.
+    /// `base` is the data set the diff applies to (the target for reversed diffs).
+    /// Features are ordered by location, longer ones (usually parents) first.
+    pub(crate) fn diff_features(
+        &self,
+        diff: &Value,
+        base: DataSet,
+    ) -> Result<Vec<ChangedFeature>, Box<dyn Error>> {
+        let data = self.loaded_data(base)?;
+        let changes = match diff["changes"].as_array() {
+            Some(changes) => changes,
+            None => return Err(From::from("diff_features: No changes in diff")),
+        };
+
+        // Group changes per feature, in order of appearance
+        let mut ids: Vec<&str> = vec![];
+        let mut feature_changes: HashMap<&str, Vec<&Value>> = HashMap::new();
+        for change in changes {
+            let id = match change["id"].as_str() {
+                Some(id) => id,
+                None => return Err(From::from(format!("diff_features: No ID in {}", change))),
+            };
+            if !feature_changes.contains_key(id) {
+                ids.push(id);
+            }
+            feature_changes.entry(id).or_default().push(change);
+        }
+
+        let mut ret: Vec<ChangedFeature> = vec![];
+        for id in ids {
+            let mut old = data.get(id).cloned();
+            let mut new = old.clone();
+            for change in &feature_changes[id] {
+                match (change["what"].as_str(), change["action"].as_str()) {
+                    (Some("row"), Some("add")) => {
+                        new = Some(Self::gff_from_json(&change["added_element"])?)
+                    }
+                    (Some("row"), Some("remove")) => {
+                        if old.is_none() && change["removed_element"].is_object() {
+                            old = Some(Self::gff_from_json(&change["removed_element"])?);
+                        }
+                        new = None;
+                    }
+                    (Some("row"), Some("update")) => match new.as_mut() {
+                        Some(element) => Self::apply_diff_row_update(change, element)?,
+                        None => {
+                            return Err(From::from(format!("diff_features: ID {} not found", id)))
+                        }
+                    },
+                    (Some("attribute"), Some(action)) => {
+                        let element = match new.as_mut() {
+                            Some(element) => element,
+                            None => {
+                                return Err(From::from(format!(
+                                    "diff_features: ID {} not found",
+                                    id
+                                )))
+                            }
+                        };
+                        let key = change["key"].as_str().unwrap_or("").to_string();
+                        let value = change["value"].as_str().unwrap_or("").to_string();
+                        match action {
+                            "add" => {
+                                // Diffs can contain the same attribute addition more than once
+                                let values = element.attributes().get_vec(&key);
+                                if !values.is_some_and(|values| values.contains(&value)) {
+                                    Self::apply_diff_attribute_add(element, key, value)?
+                                }
+                            }
+                            _ => Self::apply_diff_attribute_remove(element, key, value)?,
+                        }
+                    }
+                    _ => {
+                        return Err(From::from(format!(
+                            "diff_features: Unknown change {}",
+                            change
+                        )))
+                    }
+                }
+            }
+            if old.is_none() && new.is_none() {
+                return Err(From::from(format!("diff_features: ID {} not found", id)));
+            }
+            ret.push(ChangedFeature {
+                id: id.to_string(),
+                old,
+                new,
+            });
+        }
+        ret.sort_by_cached_key(|feature| {
+            let e = feature.old.as_ref().or(feature.new.as_ref()).unwrap();
+            (
+                e.seqname().to_string(),
+                *e.start(),
+                std::cmp::Reverse(*e.end()),
+                feature.id.to_owned(),
+            )
+        });
+        Ok(ret)
+    }
+}
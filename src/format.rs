@@ -0,0 +1,309 @@
+use crate::{CompareGFF, HashGFF};
+use bio::io::gff;
+use std::collections::HashMap;
+use std::error::Error;
+use std::str::FromStr;
+
+/// File formats for annotation data.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GffFormat {
+    Gff3,
+    /// GTF (GFF2.5); `gene_id`/`transcript_id` are mapped to the ID/Parent hierarchy.
+    Gtf,
+    /// GFF2; like GTF, `gene_id`/`transcript_id` are mapped to the ID/Parent hierarchy, if present.
+    Gff2,
+}
+
+impl GffFormat {
+    pub(crate) fn gff_type(&self) -> gff::GffType {
+        match self {
+            GffFormat::Gff3 => gff::GffType::GFF3,
+            GffFormat::Gtf => gff::GffType::GTF2,
+            GffFormat::Gff2 => gff::GffType::GFF2,
+        }
+    }
+
+    /// Guesses the format from the start of a file, via the version header or the attributes of the first row.
+    /// Defaults to GFF3.
+    pub fn detect(head: &[u8]) -> Self {
+        let head = String::from_utf8_lossy(head);
+        for line in head.lines() {
+            if let Some(version) = line.strip_prefix("##gff-version") {
+                if version.trim().starts_with('3') {
+                    return GffFormat::Gff3;
+                }
+                continue; // GFF2 or GTF, depending on the attributes
+            }
+            if line.starts_with('#') {
+                continue;
+            }
+            let attributes = match line.split('\t').nth(8) {
+                Some(attributes) => attributes,
+                None => continue,
+            };
+            let first = attributes.split(';').next().unwrap_or("");
+            return if attributes.contains("gene_id ") || attributes.contains("transcript_id ") {
+                GffFormat::Gtf
+            } else if first.contains('=') {
+                GffFormat::Gff3
+            } else {
+                GffFormat::Gff2
+            };
+        }
+        GffFormat::Gff3
+    }
+}
+
+impl FromStr for GffFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "gff3" | "gff" => Ok(GffFormat::Gff3),
+            "gtf" | "gtf2" => Ok(GffFormat::Gtf),
+            "gff2" => Ok(GffFormat::Gff2),
+            _ => Err(format!("Unknown format '{}', use gff3, gtf, or gff2", s)),
+        }
+    }
+}
+
+impl CompareGFF {
+    /// Adds ID and Parent attributes to GTF/GFF2 records, based on `gene_id` and `transcript_id`.
+    /// Genes get their `gene_id` as ID, transcripts their `transcript_id`. Other rows (exons, CDS etc.)
+    /// get `PARENT:TYPE:NUMBER`, numbered by `exon_number` or location. `exon_id` is not used as ID, since
+    /// transcripts sharing an exon also share its `exon_id`; it is kept as a plain attribute.
+    /// Records that already have an ID are left alone.
+    pub(crate) fn gtf_add_ids(records: &mut [gff::Record]) {
+        let mut order: Vec<usize> = (0..records.len()).collect();
+        order.sort_by_key(|i| (*records[*i].start(), *records[*i].end()));
+        let mut counters: HashMap<(String, String), usize> = HashMap::new();
+        for i in order {
+            let record = &mut records[i];
+            let attributes = record.attributes();
+            if attributes.contains_key("ID") {
+                continue;
+            }
+            let gene_id = attributes.get("gene_id").cloned();
+            let transcript_id = attributes.get("transcript_id").cloned();
+            let feature_type = record.feature_type().to_string();
+            let (id, parent_id) = match (feature_type.as_str(), gene_id, transcript_id) {
+                ("gene", Some(gene_id), _) => (gene_id, None),
+                ("transcript", gene_id, Some(transcript_id))
+                | ("mRNA", gene_id, Some(transcript_id)) => (transcript_id, gene_id),
+                (_, gene_id, transcript_id) => {
+                    let parent_id = match transcript_id.or(gene_id) {
+                        Some(parent_id) => parent_id,
+                        None => continue, // No hierarchy, no ID
+                    };
+                    let number = match attributes.get("exon_number") {
+                        Some(number) => number.to_owned(),
+                        None => {
+                            let key = (parent_id.to_owned(), feature_type.to_owned());
+                            let counter = counters.entry(key).or_insert(0);
+                            *counter += 1;
+                            counter.to_string()
+                        }
+                    };
+                    let id = format!("{}:{}:{}", parent_id, feature_type, number);
+                    (id, Some(parent_id))
+                }
+            };
+            let attributes = record.attributes_mut();
+            attributes.insert("ID".to_string(), id);
+            if let Some(parent_id) = parent_id {
+                attributes.insert("Parent".to_string(), parent_id);
+            }
+        }
+    }
+
+    /// Returns the IDs of an element and all its ancestors, up to the root element.
+    fn ancestor_chain<'a>(data: &'a HashGFF, id: &'a String) -> Vec<&'a String> {
+        let mut ret = vec![id];
+        let mut id = id;
+        while let Some(parent_id) = data.get(id).and_then(|e| e.attributes().get("Parent")) {
+            if ret.contains(&parent_id) || !data.contains_key(parent_id) {
+                break; // circular ID chain, or missing parent
+            }
+            ret.push(parent_id);
+            id = parent_id;
+        }
+        ret
+    }
+
+    /// Renders the attributes of a GTF/GFF2 row, as `key "value";` pairs.
+    /// For GTF, `gene_id` and `transcript_id` come first, derived from the ID/Parent hierarchy if missing,
+    /// and ID/Parent are left out.
+    fn gtf_attributes(data: &HashGFF, id: &String, format: GffFormat) -> String {
+        let element = &data[id];
+        let mut pairs: Vec<(String, String)> = vec![];
+        let mut skip_keys: Vec<&str> = vec![];
+        if format == GffFormat::Gtf {
+            let chain = Self::ancestor_chain(data, id);
+            let attributes = element.attributes();
+            let gene_id = attributes.get("gene_id").or_else(|| chain.last().copied());
+            let transcript_id = attributes
+                .get("transcript_id")
+                .or_else(|| chain.iter().rev().nth(1).copied());
+            if let Some(gene_id) = gene_id {
+                pairs.push(("gene_id".to_string(), gene_id.to_owned()));
+            }
+            if let Some(transcript_id) = transcript_id {
+                pairs.push(("transcript_id".to_string(), transcript_id.to_owned()));
+            }
+            skip_keys = vec!["ID", "Parent", "gene_id", "transcript_id"];
+        }
+        let mut keys: Vec<&String> = element
+            .attributes()
+            .keys()
+            .filter(|key| !skip_keys.contains(&key.as_str()))
+            .collect();
+        keys.sort_by_key(|key| (*key != "ID", *key != "Parent", key.to_string()));
+        for key in keys {
+            if let Some(values) = element.attributes().get_vec(key) {
+                values
+                    .iter()
+                    .for_each(|value| pairs.push((key.to_owned(), value.to_owned())));
+            }
+        }
+        pairs
+            .iter()
+            .map(|(key, value)| format!("{} \"{}\";", key, value.replace('"', "\\\"")))
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+
+    /// Writes the data as GTF or GFF2, ordered by location, parents before children.
+    pub(crate) fn write_gtf<W: std::io::Write>(
+        mut file: W,
+        data: &HashGFF,
+        format: GffFormat,
+    ) -> Result<(), Box<dyn Error>> {
+        if format == GffFormat::Gff2 {
+            writeln!(file, "##gff-version 2")?;
+        }
+        let mut ids: Vec<&String> = data.keys().collect();
+        ids.sort_by_cached_key(|id| {
+            let e = &data[*id];
+            (
+                e.seqname().to_string(),
+                *e.start(),
+                Self::ancestor_chain(data, id).len(),
+                id.to_string(),
+            )
+        });
+        for id in ids {
+            let e = &data[id];
+            writeln!(
+                file,
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                e.seqname(),
+                e.source(),
+                e.feature_type(),
+                e.start(),
+                e.end(),
+                Self::score_text(e),
+                Self::strand_symbol(e),
+                e.frame(),
+                Self::gtf_attributes(data, id, format)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DataSet, GffSource};
+
+    const GTF: &str = "chr1\ttest\tgene\t100\t900\t.\t+\t.\tgene_id \"G1\"; gene_name \"ABC\";
+chr1\ttest\ttranscript\t100\t900\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\";
+chr1\ttest\texon\t100\t200\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\"; exon_number \"1\";
+chr1\ttest\texon\t700\t900\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\"; exon_number \"2\";
+chr1\ttest\tCDS\t150\t200\t.\t+\t0\tgene_id \"G1\"; transcript_id \"T1\";
+";
+
+    #[test]
+    fn detect_format() {
+        assert_eq!(GffFormat::detect(GTF.as_bytes()), GffFormat::Gtf);
+        assert_eq!(
+            GffFormat::detect(b"##gff-version 3\nchr1\t.\tgene\t1\t9\t.\t+\t.\tID=G1\n"),
+            GffFormat::Gff3
+        );
+        assert_eq!(
+            GffFormat::detect(b"##gff-version 2\nchr1\t.\tgene\t1\t9\t.\t+\t.\tGene G1\n"),
+            GffFormat::Gff2
+        );
+        assert_eq!("GTF".parse::<GffFormat>(), Ok(GffFormat::Gtf));
+    }
+
+    #[test]
+    fn read_gtf() {
+        let mut cg = CompareGFF::new();
+        cg.load(GffSource::text(GTF), DataSet::Base).unwrap();
+        let data1 = cg.data1.as_ref().unwrap();
+        assert_eq!(data1.len(), 5);
+        assert_eq!(
+            data1["T1"].attributes().get("Parent"),
+            Some(&"G1".to_string())
+        );
+        assert_eq!(
+            data1["T1:exon:2"].attributes().get("Parent"),
+            Some(&"T1".to_string())
+        );
+        assert!(data1.contains_key("T1:CDS:1"));
+        let hierarchy = cg.hierarchy(DataSet::Base).unwrap();
+        assert_eq!(hierarchy.root_id("T1:CDS:1"), Some(&"G1".to_string()));
+    }
+
+    #[test]
+    fn write_gtf() {
+        let mut cg = CompareGFF::new();
+        cg.load(GffSource::text(GTF), DataSet::Base).unwrap();
+        let mut out: Vec<u8> = vec![];
+        CompareGFF::write_gtf(&mut out, cg.data1.as_ref().unwrap(), GffFormat::Gtf).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert!(lines[0].ends_with("\tgene_id \"G1\"; gene_name \"ABC\";"));
+        assert!(lines[2].ends_with("\tgene_id \"G1\"; transcript_id \"T1\"; exon_number \"1\";"));
+
+        // Round trip
+        let mut cg2 = CompareGFF::new();
+        cg2.load(GffSource::text(GTF), DataSet::Base).unwrap();
+        cg2.load(GffSource::text(out), DataSet::Target).unwrap();
+        assert_eq!(cg2.diff().unwrap()["changes"], json!([]));
+    }
+
+    #[test]
+    fn write_gtf_score() {
+        let gtf = "chr1\ttest\tgene\t100\t900\t0.5\t+\t.\tgene_id \"G1\";\n";
+        let mut cg = CompareGFF::new();
+        cg.load(GffSource::text(gtf), DataSet::Base).unwrap();
+        let mut out: Vec<u8> = vec![];
+        CompareGFF::write_gtf(&mut out, cg.data1.as_ref().unwrap(), GffFormat::Gtf).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), gtf);
+    }
+
+    #[test]
+    fn gtf_shared_exon() {
+        let mut gtf = String::new();
+        for transcript_id in &["T1", "T2", "T3"] {
+            gtf += &format!("chr1\ttest\texon\t100\t200\t.\t+\t.\tgene_id \"G1\"; transcript_id \"{}\"; exon_id \"E1\"; exon_number \"1\";\n", transcript_id);
+        }
+        let mut cg = CompareGFF::new();
+        cg.record_issues(true);
+        cg.load(GffSource::text(gtf), DataSet::Base).unwrap();
+        let data1 = cg.data1.as_ref().unwrap();
+        assert_eq!(data1.len(), 3);
+        for transcript_id in &["T1", "T2", "T3"] {
+            let exon = &data1[&format!("{}:exon:1", transcript_id)];
+            assert_eq!(
+                exon.attributes().get("Parent"),
+                Some(&transcript_id.to_string())
+            );
+            assert_eq!(exon.attributes().get("exon_id"), Some(&"E1".to_string()));
+        }
+        assert!(cg.issues.is_empty());
+    }
+}
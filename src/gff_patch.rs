@@ -0,0 +1,104 @@
+use crate::{CompareGFF, DataSet};
+use bio::io::gff;
+use serde_json::value::Value;
+use std::error::Error;
+use std::io::Write;
+
+impl CompareGFF {
+    /// Writes the features affected by a diff as GFF3, for display in a genome browser.
+    /// Added and modified features are written in their new state, removed ones in their old state.
+    /// Each gets a `diff_status` attribute (`added`, `removed`, or `modified`); modified features also get
+    /// `diff_changes`, the changed columns and attribute keys.
+    /// `base` is the data set the diff applies to (the target for reversed diffs).
+    pub fn write_diff_gff<W: Write>(
+        &self,
+        mut file: W,
+        diff: &Value,
+        base: DataSet,
+    ) -> Result<(), Box<dyn Error>> {
+        let features = self.diff_features(diff, base)?;
+        writeln!(file, "##gff-version 3")?;
+        let mut elements: Vec<gff::Record> = Vec::with_capacity(features.len());
+        for feature in &features {
+            let mut element = feature.element().clone();
+            let mut changes: Vec<String> = feature
+                .changed_columns()
+                .iter()
+                .map(|column| column.to_string())
+                .collect();
+            changes.append(&mut feature.changed_attributes());
+            let attributes = element.attributes_mut();
+            attributes.remove("diff_status");
+            attributes.remove("diff_changes");
+            attributes.insert("diff_status".to_string(), feature.status().to_string());
+            if !changes.is_empty() {
+                attributes.insert("diff_changes".to_string(), changes.join(","));
+            }
+            elements.push(element);
+        }
+        Self::write_gff3_records(&mut file, &elements)?;
+        file.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::GffSource;
+
+    #[test]
+    fn write_diff_gff() {
+        let mut cg = CompareGFF::new();
+        cg.load_test_files("gene_split", "core.gff", "cap.gff");
+        let diff = cg.diff().unwrap();
+        let mut gff: Vec<u8> = vec![];
+        cg.write_diff_gff(&mut gff, &diff, DataSet::Base).unwrap();
+        let gff = String::from_utf8(gff).unwrap();
+        assert!(gff.starts_with("##gff-version 3\n"));
+
+        let mut patch = CompareGFF::new();
+        patch.load(GffSource::text(gff), DataSet::Base).unwrap();
+        let data = patch.data1.as_ref().unwrap();
+        let attribute = |id: &str, key: &str| data[id].attributes().get(key).cloned();
+        assert_eq!(
+            attribute("G0001", "diff_status"),
+            Some("modified".to_string())
+        );
+        assert_eq!(attribute("G0001", "diff_changes"), Some("end".to_string()));
+        assert_eq!(
+            attribute("C0004", "diff_changes"),
+            Some("Parent".to_string())
+        );
+        assert_eq!(attribute("C0004", "Parent"), Some("M0002".to_string()));
+        assert_eq!(attribute("G0002", "diff_status"), Some("added".to_string()));
+        assert_eq!(attribute("G0002", "diff_changes"), None);
+    }
+
+    /// A writer that fails after the GFF header, like a full disk.
+    struct HeaderOnly(usize);
+
+    impl Write for HeaderOnly {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.0 + buf.len() > "##gff-version 3\n".len() {
+                return Err(std::io::Error::other("disk full"));
+            }
+            self.0 += buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn write_diff_gff_errors() {
+        let mut cg = CompareGFF::new();
+        cg.load_test_files("gene_split", "core.gff", "cap.gff");
+        let diff = cg.diff().unwrap();
+        assert!(cg
+            .write_diff_gff(HeaderOnly(0), &diff, DataSet::Base)
+            .is_err());
+    }
+}
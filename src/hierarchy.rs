@@ -0,0 +1,131 @@
+use crate::HashGFF;
+use std::collections::HashMap;
+
+/// Parent/child index of a GFF data set, built once so hierarchy lookups do not need to walk the Parent chain.
+#[derive(Clone, Debug, Default)]
+pub struct Hierarchy {
+    root: HashMap<String, String>,
+    depth: HashMap<String, usize>,
+    children: HashMap<String, Vec<String>>,
+    members: HashMap<String, Vec<String>>,
+}
+
+impl Hierarchy {
+    /// Builds the index for a data set.
+    /// Elements with a missing parent, or in a circular Parent chain, have no root.
+    pub fn new(data: &HashGFF) -> Self {
+        let mut ret = Self::default();
+        for (id, element) in data {
+            if let Some(parent_ids) = element.attributes().get_vec("Parent") {
+                for parent_id in parent_ids {
+                    ret.children
+                        .entry(parent_id.to_owned())
+                        .or_default()
+                        .push(id.to_owned());
+                }
+            }
+        }
+        for id in data.keys() {
+            ret.resolve(data, id);
+        }
+        for (id, root_id) in &ret.root {
+            ret.members
+                .entry(root_id.to_owned())
+                .or_default()
+                .push(id.to_owned());
+        }
+        ret.children.values_mut().for_each(|ids| ids.sort());
+        ret.members.values_mut().for_each(|ids| ids.sort());
+        ret
+    }
+
+    /// Finds root and depth of an element, and of all its ancestors on the way.
+    fn resolve(&mut self, data: &HashGFF, id: &String) {
+        let mut chain: Vec<&String> = vec![];
+        let mut current = id;
+        let (root_id, root_depth) = loop {
+            if let (Some(root_id), Some(depth)) = (self.root.get(current), self.depth.get(current))
+            {
+                break (root_id.to_owned(), *depth);
+            }
+            if chain.contains(&current) {
+                return; // circular ID chain, oh no
+            }
+            let element = match data.get(current) {
+                Some(element) => element,
+                None => return, // Missing parent
+            };
+            chain.push(current);
+            match element.attributes().get("Parent") {
+                Some(parent_id) => current = parent_id,
+                None => {
+                    chain.pop();
+                    self.root.insert(current.to_owned(), current.to_owned());
+                    self.depth.insert(current.to_owned(), 0);
+                    break (current.to_owned(), 0);
+                }
+            }
+        };
+        for (distance, id) in chain.iter().rev().enumerate() {
+            self.root.insert(id.to_string(), root_id.to_owned());
+            self.depth.insert(id.to_string(), root_depth + distance + 1);
+        }
+    }
+
+    /// Returns the ID of the root element (e.g. the gene of an exon), or the element itself if it has no parent.
+    pub fn root_id(&self, id: &str) -> Option<&String> {
+        self.root.get(id)
+    }
+
+    /// Returns the number of ancestors of an element.
+    pub fn depth(&self, id: &str) -> Option<usize> {
+        self.depth.get(id).copied()
+    }
+
+    /// Returns the IDs of the direct children of an element.
+    pub fn children(&self, id: &str) -> &[String] {
+        self.children.get(id).map_or(&[], |ids| ids.as_slice())
+    }
+
+    /// Returns the IDs of all elements with the given root element, including the root itself.
+    pub fn members(&self, root_id: &str) -> &[String] {
+        self.members.get(root_id).map_or(&[], |ids| ids.as_slice())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CompareGFF, DataSet, GffSource};
+
+    #[test]
+    fn hierarchy() {
+        let mut cg = CompareGFF::new();
+        cg.load(
+            GffSource::file("test/apollo_exon_move/core.gff"),
+            DataSet::Base,
+        )
+        .unwrap();
+        let h = cg.hierarchy(DataSet::Base).unwrap();
+        assert_eq!(h.root_id("E0003"), Some(&"G0001".to_string()));
+        assert_eq!(h.root_id("G0001"), Some(&"G0001".to_string()));
+        assert_eq!(h.root_id("NOPE"), None);
+        assert_eq!(h.depth("G0001"), Some(0));
+        assert_eq!(h.depth("E0005"), Some(2));
+        assert_eq!(h.children("M0002"), ["E0004", "E0005"]);
+        assert_eq!(h.members("G0001").len(), 8);
+    }
+
+    #[test]
+    fn hierarchy_circular() {
+        let data = CompareGFF::new()
+            .read_source(crate::GffSource::text(
+                "chr1\t.\texon\t1\t10\t.\t+\t.\tID=A;Parent=B\nchr1\t.\texon\t1\t10\t.\t+\t.\tID=B;Parent=A\nchr1\t.\texon\t1\t10\t.\t+\t.\tID=C;Parent=A\n",
+            ))
+            .unwrap();
+        let h = Hierarchy::new(&data);
+        assert_eq!(h.root_id("A"), None);
+        assert_eq!(h.root_id("C"), None);
+        assert_eq!(h.children("A"), ["B", "C"]);
+    }
+}
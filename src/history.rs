@@ -0,0 +1,142 @@
+use crate::{CompareGFF, GffSource};
+use rayon::prelude::*;
+use serde_json::value::Value;
+use std::collections::BTreeSet;
+use std::error::Error;
+
+impl CompareGFF {
+    /// Loads an additional annotation version, for `history`. Versions are kept in the order they are loaded.
+    /// Versions are separate from the base and target data sets, which `diff` and `load` work on.
+    /// Returns the index of the new version.
+    pub fn load_gff_version<S: Into<String>>(
+        &mut self,
+        filename: S,
+    ) -> Result<usize, Box<dyn Error>> {
+        let filename: String = filename.into();
+        self.load_version(filename.to_owned(), GffSource::file(filename))
+    }
+
+    /// Loads an additional annotation version from a source, with a label to identify it in the `history`.
+    /// Returns the index of the new version.
+    pub fn load_version<S: Into<String>>(
+        &mut self,
+        label: S,
+        source: GffSource,
+    ) -> Result<usize, Box<dyn Error>> {
+        let data = self.read_source(source)?;
+        self.versions.push((label.into(), data));
+        Ok(self.versions.len() - 1)
+    }
+
+    /// Creates a new CompareGFF with an annotation version for each file, in the given order.
+    pub fn new_from_versions<S: Into<String>>(filenames: Vec<S>) -> Result<Self, Box<dyn Error>> {
+        let mut ret = Self::new();
+        for filename in filenames {
+            ret.load_gff_version(filename)?;
+        }
+        Ok(ret)
+    }
+
+    /// Traces the history of a single feature across all loaded versions.
+    fn feature_history(&self, id: &String) -> Value {
+        let mut present: Vec<usize> = vec![];
+        let mut events: Vec<Value> = vec![];
+        let mut previous: Option<&bio::io::gff::Record> = None;
+        let mut latest: Option<&bio::io::gff::Record> = None;
+        for (version, (_label, data)) in self.versions.iter().enumerate() {
+            let current = data.get(id);
+            match (previous, current) {
+                (None, Some(_)) if version > 0 => {
+                    events.push(json!({"version":version,"action":"add"}))
+                }
+                (Some(_), None) => events.push(json!({"version":version,"action":"remove"})),
+                (Some(r1), Some(r2)) => {
+                    let mut attribute_changes =
+                        Self::compare_attribute_maps(id, r1.attributes(), r2.attributes());
+                    // Added values are found in both directions of the comparison
+                    attribute_changes.sort_by_cached_key(|change| change.to_string());
+                    attribute_changes.dedup();
+                    self.compare_basics(r1, r2, id.as_str())
+                        .into_iter()
+                        .chain(attribute_changes)
+                        .for_each(|mut change| {
+                            change["version"] = json!(version);
+                            events.push(change);
+                        })
+                }
+                _ => {}
+            }
+            if let Some(element) = current {
+                present.push(version);
+                latest = Some(element);
+            }
+            previous = current;
+        }
+        let mut ret = json!({"id":id,"versions":present,"events":events});
+        if let Some(element) = latest {
+            ret["seqname"] = json!(element.seqname());
+            ret["feature_type"] = json!(element.feature_type());
+        }
+        ret
+    }
+
+    /// Generates a per-feature history across all versions loaded via `load_gff_version`:
+    /// which versions contain a feature, and in which version it was added, removed, or its columns or attributes changed.
+    pub fn history(&self) -> Result<Value, Box<dyn Error>> {
+        if self.versions.len() < 2 {
+            return Err(From::from("At least two versions need to be loaded"));
+        }
+        let ids: BTreeSet<&String> = self
+            .versions
+            .iter()
+            .flat_map(|(_label, data)| data.keys())
+            .collect();
+        let features: Vec<Value> = ids.par_iter().map(|id| self.feature_history(id)).collect();
+        let versions: Vec<&String> = self.versions.iter().map(|(label, _data)| label).collect();
+        Ok(json!({"versions":versions,"features":features}))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn history() {
+        let dir = "test/merge_three_way";
+        let cg = CompareGFF::new_from_versions(vec![
+            format!("{}/base.gff", dir),
+            format!("{}/ours.gff", dir),
+            format!("{}/theirs.gff", dir),
+        ])
+        .unwrap();
+        let history = cg.history().unwrap();
+        assert_eq!(history["versions"].as_array().unwrap().len(), 3);
+        let feature = |id: &str| {
+            history["features"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|feature| feature["id"] == id)
+                .unwrap()
+                .to_owned()
+        };
+
+        let gene = feature("G0001");
+        assert_eq!(gene["versions"], json!([0, 1, 2]));
+        assert_eq!(gene["events"][0]["version"], json!(1));
+        assert_eq!(gene["events"][0]["value"], json!("1200"));
+        assert_eq!(gene["events"][1]["old_value"], json!("1200"));
+        assert_eq!(gene["events"][1]["value"], json!("1100"));
+
+        let exon = feature("E0003");
+        assert_eq!(exon["versions"], json!([0, 1]));
+        assert_eq!(exon["events"], json!([{"version":2,"action":"remove"}]));
+
+        let exon = feature("E0002");
+        assert_eq!(
+            exon["events"],
+            json!([{"version":2,"action":"add","what":"attribute","id":"E0002","key":"Note","value":"checked"}])
+        );
+    }
+}
@@ -0,0 +1,305 @@
+use crate::diff_features::{change_values, changes_by_id, value_text, ChangedFeature};
+use crate::{CompareGFF, DataSet};
+use bio::io::gff;
+use serde_json::value::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+
+const HTML_STYLE: &str = "body{font-family:sans-serif;margin:2em}
+table{border-collapse:collapse;margin-bottom:1em}
+th,td{border:1px solid #ccc;padding:2px 8px;text-align:left;font-size:90%}
+th{background:#eee}
+.added{color:#080}.removed{color:#c00}
+svg{display:block;margin-bottom:1em}";
+
+const SVG_WIDTH: u64 = 800;
+const SVG_LABEL_WIDTH: u64 = 140;
+const SVG_ROW_HEIGHT: u64 = 20;
+
+/// Parent/child relationships of the features in one state (before or after the diff).
+struct ModelState<'a> {
+    elements: HashMap<&'a str, &'a gff::Record>,
+    children: HashMap<&'a str, Vec<&'a str>>,
+}
+
+impl<'a> ModelState<'a> {
+    fn new(elements: HashMap<&'a str, &'a gff::Record>) -> Self {
+        let mut children: HashMap<&'a str, Vec<&'a str>> = HashMap::new();
+        for (id, element) in &elements {
+            for parent_id in element.attributes().get_vec("Parent").into_iter().flatten() {
+                children.entry(parent_id).or_default().push(id);
+            }
+        }
+        Self { elements, children }
+    }
+
+    fn children(&self, id: &str) -> Vec<&'a str> {
+        self.children.get(id).cloned().unwrap_or_default()
+    }
+
+    /// Returns the exons of a transcript (or the CDS, if it has no exons), by location.
+    fn exons(&self, transcript_id: &str) -> Vec<&'a gff::Record> {
+        let children: Vec<&gff::Record> = self
+            .children(transcript_id)
+            .iter()
+            .filter_map(|id| self.elements.get(id).copied())
+            .collect();
+        let mut ret: Vec<&gff::Record> = ["exon", "CDS"]
+            .iter()
+            .map(|feature_type| {
+                children
+                    .iter()
+                    .filter(|e| e.feature_type() == *feature_type)
+                    .copied()
+                    .collect::<Vec<&gff::Record>>()
+            })
+            .find(|exons| !exons.is_empty())
+            .unwrap_or_default();
+        ret.sort_by_key(|e| *e.start());
+        ret
+    }
+}
+
+/// Escapes text for HTML.
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+impl CompareGFF {
+    /// Generates a self-contained HTML report of a diff: summary statistics, a table of changes per gene,
+    /// SVG drawings of the old and new exon structure of each changed transcript, and the issues list.
+    /// `base` is the data set the diff applies to (the target for reversed diffs).
+    pub fn diff_html(&self, diff: &Value, base: DataSet) -> Result<String, Box<dyn Error>> {
+        let data = self.loaded_data(base)?;
+        let changed_features = self.diff_features(diff, base)?;
+        let features = ChangedFeature::by_id(&changed_features);
+
+        // Model before and after the diff
+        let mut old_elements: HashMap<&str, &gff::Record> =
+            data.iter().map(|(id, e)| (id.as_str(), e)).collect();
+        let mut new_elements = old_elements.clone();
+        for feature in &changed_features {
+            let id = feature.id.as_str();
+            match &feature.old {
+                Some(old) => old_elements.insert(id, old),
+                None => old_elements.remove(id),
+            };
+            match &feature.new {
+                Some(new) => new_elements.insert(id, new),
+                None => new_elements.remove(id),
+            };
+        }
+        let old_state = ModelState::new(old_elements);
+        let new_state = ModelState::new(new_elements);
+
+        // Changes per feature, and changed features per gene (in order of location)
+        let changes = changes_by_id(diff);
+        let mut gene_ids: Vec<String> = vec![];
+        let mut gene_features: HashMap<String, Vec<&ChangedFeature>> = HashMap::new();
+        for feature in &changed_features {
+            let gene_id = Self::table_root_id(&feature.id, &features, data);
+            if !gene_features.contains_key(&gene_id) {
+                gene_ids.push(gene_id.to_owned());
+            }
+            gene_features.entry(gene_id).or_default().push(feature);
+        }
+
+        let mut html = format!(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>gff_diff report</title>\n<style>\n{}\n</style>\n</head>\n<body>\n<h1>gff_diff report</h1>\n",
+            HTML_STYLE
+        );
+        html += &Self::html_summary(diff, &changed_features, gene_ids.len());
+        for gene_id in &gene_ids {
+            let gene_features = &gene_features[gene_id];
+            let gene = new_state
+                .elements
+                .get(gene_id.as_str())
+                .or_else(|| old_state.elements.get(gene_id.as_str()));
+            html += &format!(
+                "<h2 id=\"{}\">{}",
+                html_escape(gene_id),
+                html_escape(gene_id)
+            );
+            if let Some(gene) = gene {
+                if let Some(name) = gene.attributes().get("Name") {
+                    html += &format!(" ({})", html_escape(name));
+                }
+                html += &format!(
+                    " <small>{}:{}-{}</small>",
+                    html_escape(gene.seqname()),
+                    gene.start(),
+                    gene.end()
+                );
+            }
+            html += "</h2>\n";
+
+            html += "<table>\n<tr><th>id</th><th>feature_type</th><th>what</th><th>action</th><th>key</th><th>old</th><th>new</th></tr>\n";
+            for feature in gene_features {
+                for change in changes.get(feature.id.as_str()).into_iter().flatten() {
+                    let (old, new) = change_values(change);
+                    let action = value_text(&change["action"]);
+                    html += &format!(
+                        "<tr><td>{}</td><td>{}</td><td>{}</td><td class=\"{}\">{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+                        html_escape(&feature.id),
+                        html_escape(feature.element().feature_type()),
+                        html_escape(&value_text(&change["what"])),
+                        match action.as_str() {
+                            "add" => "added",
+                            "remove" => "removed",
+                            _ => "",
+                        },
+                        html_escape(&action),
+                        html_escape(&value_text(&change["key"])),
+                        html_escape(&old),
+                        html_escape(&new)
+                    );
+                }
+            }
+            html += "</table>\n";
+
+            // Transcripts of the gene, before and after, that changed themselves or in their exons
+            let mut transcript_ids: Vec<&str> = old_state.children(gene_id);
+            transcript_ids.append(&mut new_state.children(gene_id));
+            transcript_ids.sort_unstable();
+            transcript_ids.dedup();
+            for transcript_id in transcript_ids {
+                let changed = features.contains_key(transcript_id)
+                    || old_state
+                        .children(transcript_id)
+                        .iter()
+                        .chain(new_state.children(transcript_id).iter())
+                        .any(|id| features.contains_key(id));
+                let old_exons = old_state.exons(transcript_id);
+                let new_exons = new_state.exons(transcript_id);
+                if changed && !(old_exons.is_empty() && new_exons.is_empty()) {
+                    html += &Self::html_svg(transcript_id, &old_exons, &new_exons);
+                }
+            }
+        }
+
+        if let Some(issues) = diff["issues"].as_array() {
+            if !issues.is_empty() {
+                html += "<h2>Issues</h2>\n<ul>\n";
+                for issue in issues {
+                    html += &format!("<li>{}</li>\n", html_escape(&value_text(issue)));
+                }
+                html += "</ul>\n";
+            }
+        }
+        html += "</body>\n</html>\n";
+        Ok(html)
+    }
+
+    /// Renders the summary statistics of a diff.
+    fn html_summary(diff: &Value, features: &[ChangedFeature], genes: usize) -> String {
+        let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+        for feature in features {
+            *counts
+                .entry(format!("Features {}", feature.status()))
+                .or_default() += 1;
+        }
+        for change in diff["changes"].as_array().into_iter().flatten() {
+            let key = format!(
+                "Changes: {} {}",
+                value_text(&change["what"]),
+                value_text(&change["action"])
+            );
+            *counts.entry(key).or_default() += 1;
+        }
+        let mut ret = "<h2>Summary</h2>\n<table>\n".to_string();
+        ret += &format!("<tr><th>Genes affected</th><td>{}</td></tr>\n", genes);
+        for (key, count) in counts {
+            ret += &format!(
+                "<tr><th>{}</th><td>{}</td></tr>\n",
+                html_escape(&key),
+                count
+            );
+        }
+        ret + "</table>\n"
+    }
+
+    /// Draws the old and new exon structure of a transcript as SVG, one row each.
+    fn html_svg(transcript_id: &str, old: &[&gff::Record], new: &[&gff::Record]) -> String {
+        let all = old.iter().chain(new.iter());
+        let start = all
+            .clone()
+            .map(|e| e.start().saturating_sub(1))
+            .min()
+            .unwrap_or(0);
+        let end = all.map(|e| *e.end()).max().unwrap_or(0).max(start + 1);
+        let scale = (SVG_WIDTH - SVG_LABEL_WIDTH) as f64 / (end - start) as f64;
+        let x = |pos: u64| SVG_LABEL_WIDTH as f64 + (pos - start) as f64 * scale;
+        let mut ret = format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\">\n<title>{}</title>\n",
+            SVG_WIDTH,
+            SVG_ROW_HEIGHT * 2,
+            html_escape(transcript_id)
+        );
+        for (row, (label, exons, color)) in [("old", old, "#c66"), ("new", new, "#6a6")]
+            .iter()
+            .enumerate()
+        {
+            let y = row as u64 * SVG_ROW_HEIGHT;
+            let mid = y + SVG_ROW_HEIGHT / 2;
+            ret += &format!(
+                "<text x=\"0\" y=\"{}\" font-size=\"12\">{} {}</text>\n",
+                mid + 4,
+                html_escape(transcript_id),
+                label
+            );
+            if let (Some(first), Some(last)) = (exons.first(), exons.last()) {
+                ret += &format!(
+                    "<line x1=\"{:.1}\" y1=\"{}\" x2=\"{:.1}\" y2=\"{}\" stroke=\"#999\"/>\n",
+                    x(*first.start()),
+                    mid,
+                    x(*last.end()),
+                    mid
+                );
+            }
+            for exon in exons.iter() {
+                ret += &format!(
+                    "<rect x=\"{:.1}\" y=\"{}\" width=\"{:.1}\" height=\"{}\" fill=\"{}\"><title>{}-{}</title></rect>\n",
+                    x(exon.start().saturating_sub(1)),
+                    y + 4,
+                    (x(*exon.end()) - x(exon.start().saturating_sub(1))).max(1.0),
+                    SVG_ROW_HEIGHT - 8,
+                    color,
+                    exon.start(),
+                    exon.end()
+                );
+            }
+        }
+        ret + "</svg>\n"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff_html() {
+        let mut cg = CompareGFF::new();
+        cg.load_test_files("alter_exon", "core.gff", "cap.gff");
+        let diff = cg.diff().unwrap();
+        let html = cg.diff_html(&diff, DataSet::Base).unwrap();
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(html.contains("<tr><th>Genes affected</th><td>1</td></tr>"));
+        assert!(html.contains("<h2 id=\"G0001\">G0001 (Gene1)"));
+        assert!(html.contains("<td>E0005</td><td>exon</td><td>row</td><td class=\"\">update</td><td>end</td><td>900</td><td>1200</td>"));
+        assert_eq!(html.matches("<svg ").count(), 1);
+        assert!(html.contains("<title>800-1200</title>"));
+        assert!(!html.contains("<h2>Issues</h2>"));
+    }
+
+    #[test]
+    fn html_escape_text() {
+        assert_eq!(
+            html_escape("<a href=\"x\">&</a>"),
+            "&lt;a href=&quot;x&quot;&gt;&amp;&lt;/a&gt;"
+        );
+    }
+}
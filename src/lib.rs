@@ -1,24 +1,51 @@
 extern crate bio;
 #[macro_use]
 extern crate serde_json;
-#[macro_use]
-extern crate lazy_static;
 
 use bio::io::gff;
 use ignore_result::Ignore;
-use libflate::gzip::Decoder;
 use multimap::MultiMap;
 use rayon::prelude::*;
-use regex::Regex;
 use serde_json::value::Value;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::error::Error;
-use std::fs::File;
+use std::io::{BufRead, BufReader};
 use std::sync::{Arc, Mutex};
 
+mod apollo_json;
+mod bed;
+mod compose;
+mod compression;
+mod diff_features;
+mod format;
+mod gff_patch;
+mod hierarchy;
+mod history;
+mod html;
+mod merge;
+mod ndjson;
+mod reconcile;
+mod schema;
+mod source;
+mod stats;
+mod tabix;
+mod table;
+mod text_diff;
+
+pub use compression::{BgzfWriter, CompressedWriter, Compression};
+pub use format::GffFormat;
+pub use hierarchy::Hierarchy;
+use reconcile::ApolloMatch;
+pub use schema::{DIFF_FORMAT_VERSION, DIFF_SCHEMA};
+pub use source::{CompareGFFBuilder, DataSet, GffSource};
+pub use tabix::Region;
+
 type HashGFF = HashMap<String, bio::io::gff::Record>;
 
+/// Number of records written at a time as GFF3.
+const GFF_WRITE_CHUNK_SIZE: usize = 10000;
+
 pub enum CompareMode {
     Forward,
     Reverse,
@@ -27,19 +54,45 @@ pub enum CompareMode {
 pub struct CompareGFF {
     data1: Option<HashGFF>,
     data2: Option<HashGFF>,
+    hierarchy1: Option<Hierarchy>,
+    hierarchy2: Option<Hierarchy>,
+    versions: Vec<(String, HashGFF)>,
     record_issues: bool,
     flexible_ids: bool,
+    input_format: Option<GffFormat>,
+    region: Option<Region>,
+    apollo_id_prefix: String,
+    apollo_ignored_attributes: Vec<String>,
+    apollo_compared_attributes: Option<Vec<String>>,
     issues: Vec<String>,
 }
 
+impl Default for CompareGFF {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl CompareGFF {
     /// Creates a new, blank CompareGFF.
     pub fn new() -> Self {
         Self {
             data1: None,
             data2: None,
+            hierarchy1: None,
+            hierarchy2: None,
+            versions: vec![],
             record_issues: false,
             flexible_ids: true,
+            input_format: None,
+            region: None,
+            apollo_id_prefix: "APOLLO_".to_string(),
+            apollo_ignored_attributes: vec![
+                "owner".to_string(),
+                "date_*".to_string(),
+                "orig_id".to_string(),
+            ],
+            apollo_compared_attributes: None,
             issues: vec![],
         }
     }
@@ -54,63 +107,97 @@ impl CompareGFF {
         self.flexible_ids = use_flexible_ids;
     }
 
+    /// Changes the format of loaded files; `None` (default) detects the format from the file content.
+    pub fn input_format(&mut self, format: Option<GffFormat>) {
+        self.input_format = format;
+    }
+
+    /// Restricts loaded data to elements overlapping a region; `None` (default) loads everything.
+    /// Files with a tabix (`.tbi`) or CSI (`.csi`) index only have the blocks covering the region decompressed.
+    pub fn region(&mut self, region: Option<Region>) {
+        self.region = region;
+    }
+
+    /// Changes the prefix for IDs of new elements in Apollo diffs.
+    pub fn apollo_id_prefix<S: Into<String>>(&mut self, prefix: S) {
+        self.apollo_id_prefix = prefix.into();
+    }
+
+    /// Changes the attribute keys ignored in Apollo diffs. A trailing `*` matches any suffix.
+    pub fn apollo_ignored_attributes(&mut self, keys: Vec<String>) {
+        self.apollo_ignored_attributes = keys;
+    }
+
+    /// Changes the attribute keys compared in Apollo diffs; `None` compares all keys that are not ignored.
+    /// A trailing `*` matches any suffix.
+    pub fn apollo_compared_attributes(&mut self, keys: Option<Vec<String>>) {
+        self.apollo_compared_attributes = keys;
+    }
+
     /// Creates a new CompareGFF with two files.
     pub fn new_from_files<S: Into<String>>(
         filename1: S,
         filename2: S,
     ) -> Result<Self, Box<dyn Error>> {
         let mut ret = Self::new();
-        ret.load_gff(filename1, 1)?;
-        ret.load_gff(filename2, 2)?;
+        ret.load(GffSource::file(filename1), DataSet::Base)?;
+        ret.load(GffSource::file(filename2), DataSet::Target)?;
         Ok(ret)
     }
 
-    fn get_file_reader<S: Into<String>>(
-        filename: S,
-    ) -> Result<Box<dyn std::io::Read>, Box<dyn Error>> {
-        lazy_static! {
-            static ref RE_GZ: Regex =
-                Regex::new(r#"\.gz(ip){0,1}$"#).expect("new_from_files: RE_GZ does not compile");
-        }
-        let filename: String = filename.into();
-        if RE_GZ.is_match(&filename) {
-            let file_stream = File::open(filename)?;
-            let decoder = Decoder::new(file_stream)?;
-            Ok(Box::new(decoder))
-        } else {
-            let file_stream = File::open(filename)?;
-            Ok(Box::new(file_stream))
-        }
-    }
-
+    /// Loads a GFF file into data set 1 (base) or 2 (target).
+    /// Prefer `load`, which also supports readers, strings, and records.
     pub fn load_gff<S: Into<String>>(
         &mut self,
         filename: S,
         data_set: u8,
     ) -> Result<(), Box<dyn Error>> {
-        //let data = Some(self.read(Box::new(File::open(filename.into())?))?);
-        let reader = Self::get_file_reader(filename)?;
-        let data = Some(self.read(reader)?);
-        match data_set {
-            1 => self.data1 = data,
-            2 => self.data2 = data,
+        let data_set = match data_set {
+            1 => DataSet::Base,
+            2 => DataSet::Target,
             _ => {
                 return Err(From::from(format!(
                     "Data set number not 1 or 2: {}",
                     data_set
                 )))
             }
+        };
+        self.load(GffSource::file(filename), data_set)
+    }
+
+    /// Loads GFF data from a source into a data set.
+    pub fn load(&mut self, source: GffSource, data_set: DataSet) -> Result<(), Box<dyn Error>> {
+        let data = self.read_source(source)?;
+        let hierarchy = Some(Hierarchy::new(&data));
+        match data_set {
+            DataSet::Base => {
+                self.data1 = Some(data);
+                self.hierarchy1 = hierarchy;
+            }
+            DataSet::Target => {
+                self.data2 = Some(data);
+                self.hierarchy2 = hierarchy;
+            }
         }
         Ok(())
     }
 
+    /// Returns the parent/child index of a loaded data set.
+    pub fn hierarchy(&self, data_set: DataSet) -> Option<&Hierarchy> {
+        match data_set {
+            DataSet::Base => self.hierarchy1.as_ref(),
+            DataSet::Target => self.hierarchy2.as_ref(),
+        }
+    }
+
     /// Generates the diff between the two loaded files.
     pub fn diff(&self) -> Result<Value, Box<dyn Error>> {
-        let mut result = json!( {
-            "changes" :[]
-        });
-        self.compare(CompareMode::Forward, &mut result)?;
-        self.compare(CompareMode::Reverse, &mut result)?;
+        let changes: Mutex<Vec<Value>> = Mutex::new(vec![]);
+        let emit = |mut new_changes: Vec<Value>| changes.lock().unwrap().append(&mut new_changes);
+        self.compare(CompareMode::Forward, emit)?;
+        self.compare(CompareMode::Reverse, emit)?;
+        let mut result =
+            json!({ "format_version": DIFF_FORMAT_VERSION, "changes": changes.into_inner()? });
         if self.record_issues {
             result["issues"] = json!(self.issues);
         }
@@ -119,16 +206,13 @@ impl CompareGFF {
 
     /// Sorts a comparison JSON. Potentially slow. Used in tests.
     pub fn sort_comparison(result: &mut Value) {
-        match result["changes"].as_array_mut() {
-            Some(changes) => {
-                changes.par_sort_by(|a, b| {
-                    let a = serde_json::to_string(a).unwrap();
-                    let b = serde_json::to_string(b).unwrap();
-                    a.partial_cmp(&b).unwrap()
-                });
-                result["changes"] = json!(changes);
-            }
-            None => {}
+        if let Some(changes) = result["changes"].as_array_mut() {
+            changes.par_sort_by(|a, b| {
+                let a = serde_json::to_string(a).unwrap();
+                let b = serde_json::to_string(b).unwrap();
+                a.partial_cmp(&b).unwrap()
+            });
+            result["changes"] = json!(changes);
         }
     }
 
@@ -167,19 +251,41 @@ impl CompareGFF {
                 return Ok(id);
             }
         }
-        return Err(format!("Double ID {}, not adding {:?}", &id, entry));
+        Err(format!("Double ID {}, not adding {:?}", &id, entry))
     }
 
     /// Reads a file from a Reader into a HashGFF hash table.
+    /// GTF/GFF2 records get ID/Parent attributes from their `gene_id`/`transcript_id`.
     fn read(&mut self, file: Box<dyn std::io::Read>) -> Result<HashGFF, Box<dyn Error>> {
-        let mut reader = gff::Reader::new(file, gff::GffType::GFF3);
+        let mut file = BufReader::with_capacity(1 << 16, file);
+        let format = match self.input_format {
+            Some(format) => format,
+            None => GffFormat::detect(file.fill_buf()?),
+        };
+        let mut reader = gff::Reader::new(file, format.gff_type());
+        let records = reader.records().filter_map(|element| element.ok());
+        if format == GffFormat::Gff3 {
+            return self.read_records(records);
+        }
+        // GTF IDs are numbered by location, so all records are needed first
+        let mut records: Vec<bio::io::gff::Record> = records.collect();
+        Self::gtf_add_ids(&mut records);
+        self.read_records(records)
+    }
 
+    /// Reads GFF records into a HashGFF hash table.
+    #[allow(clippy::unnecessary_map_or)] // Option::is_none_or needs Rust 1.82
+    fn read_records<I: IntoIterator<Item = bio::io::gff::Record>>(
+        &mut self,
+        records: I,
+    ) -> Result<HashGFF, Box<dyn Error>> {
         //TODO check for double IDs?
         let mut ids: HashSet<String> = HashSet::new();
-        let ret: HashMap<String, bio::io::gff::Record> = reader
-            .records()
-            .filter_map(|element| {
-                let e = element.ok()?;
+        let region = self.region.clone();
+        let ret: HashMap<String, bio::io::gff::Record> = records
+            .into_iter()
+            .filter(|e| region.as_ref().map_or(true, |region| region.overlaps(e)))
+            .filter_map(|e| {
                 let id = match self.unique_id(&mut ids, e.attributes().get("ID")?.to_string(), &e) {
                     Ok(id) => id,
                     Err(e) => {
@@ -191,25 +297,103 @@ impl CompareGFF {
                 Some((id, e))
             })
             .collect();
-        if ret.is_empty() {
-            return Err(From::from(format!("Empty file or no gff file")));
+        if ret.is_empty() && region.is_none() {
+            return Err(From::from("Empty file or no gff file"));
         }
         Ok(ret)
     }
 
+    /// Reads GFF data from any source into a HashGFF hash table.
+    fn read_source(&mut self, source: GffSource) -> Result<HashGFF, Box<dyn Error>> {
+        match source {
+            GffSource::File(filename) => {
+                if let Some(region) = &self.region {
+                    if let Some(index_file) = Self::tabix_index_file(&filename) {
+                        let (text, _blocks) =
+                            Self::read_tabix_region(&filename, &index_file, region)?;
+                        return self.read(Box::new(std::io::Cursor::new(text)));
+                    }
+                }
+                let reader = Self::get_file_reader(filename)?;
+                self.read(reader)
+            }
+            GffSource::Reader(reader) => self.read(Self::decompress(reader)?),
+            GffSource::Text(text) => self.read(Box::new(std::io::Cursor::new(text))),
+            GffSource::Records(records) => self.read_records(records),
+            GffSource::ApolloJson(filename) => {
+                let reader = Self::get_file_reader(filename)?;
+                let json: Value = serde_json::from_reader(reader)?;
+                let records = Self::apollo_json_records(&json)?;
+                self.read_records(records)
+            }
+        }
+    }
+
     /// Writes the GFF data. Used to construct a new file after diff.
-    fn write(&self, file: Box<dyn std::io::Write>, data: &HashGFF) -> Result<(), Box<dyn Error>> {
-        let mut writer = gff::Writer::new(file, gff::GffType::GFF3);
-        for (_k, v) in data {
-            writer.write(v)?;
+    fn write<W: std::io::Write>(
+        &self,
+        mut file: W,
+        data: &HashGFF,
+        format: GffFormat,
+    ) -> Result<(), Box<dyn Error>> {
+        if format != GffFormat::Gff3 {
+            return Self::write_gtf(file, data, format);
+        }
+        let records: Vec<&gff::Record> = data.values().collect();
+        Self::write_gff3_records(&mut file, &records)?;
+        file.flush()?;
+        Ok(())
+    }
+
+    /// Writes GFF3 records, without a header.
+    pub(crate) fn write_gff3_records<W: std::io::Write, R: std::borrow::Borrow<gff::Record>>(
+        mut file: W,
+        records: &[R],
+    ) -> Result<(), Box<dyn Error>> {
+        // The GFF writer only reports write errors until it is dropped, so it writes to a buffer
+        for chunk in records.chunks(GFF_WRITE_CHUNK_SIZE) {
+            let mut buffer: Vec<u8> = vec![];
+            let mut writer = gff::Writer::new(&mut buffer, gff::GffType::GFF3);
+            for record in chunk {
+                writer.write(record.borrow())?;
+            }
+            drop(writer);
+            file.write_all(&buffer)?;
         }
         Ok(())
     }
 
+    /// Writes data set 1 as GFF3. Use `get_file_writer` for a (compressed) output file.
     pub fn write_data1(&self, file: Box<dyn std::io::Write>) -> Result<(), Box<dyn Error>> {
+        self.write_data1_as(file, GffFormat::Gff3)
+    }
+
+    /// Writes data set 2 as GFF3. Use `get_file_writer` for a (compressed) output file.
+    pub fn write_data2(&self, file: Box<dyn std::io::Write>) -> Result<(), Box<dyn Error>> {
+        self.write_data2_as(file, GffFormat::Gff3)
+    }
+
+    /// Writes data set 1 in the given format.
+    pub fn write_data1_as<W: std::io::Write>(
+        &self,
+        file: W,
+        format: GffFormat,
+    ) -> Result<(), Box<dyn Error>> {
         match &self.data1 {
-            Some(data1) => self.write(file, &data1),
-            None => Err(From::from(format!("write_data1:: data1 is not set"))),
+            Some(data1) => self.write(file, data1, format),
+            None => Err(From::from("write_data1:: data1 is not set")),
+        }
+    }
+
+    /// Writes data set 2 in the given format.
+    pub fn write_data2_as<W: std::io::Write>(
+        &self,
+        file: W,
+        format: GffFormat,
+    ) -> Result<(), Box<dyn Error>> {
+        match &self.data2 {
+            Some(data2) => self.write(file, data2, format),
+            None => Err(From::from("write_data2:: data2 is not set")),
         }
     }
 
@@ -222,6 +406,18 @@ impl CompareGFF {
         attrs: &MultiMap<String, String>,
         mode: CompareMode,
         result: &Arc<Mutex<&mut Value>>,
+    ) {
+        Self::compare_attribute_values(id, key, values, attrs, mode, result)
+    }
+
+    /// Compares the values of one attribute key of a GFF element with the attributes of another one.
+    fn compare_attribute_values(
+        id: &String,
+        key: &String,
+        values: &Vec<String>,
+        attrs: &MultiMap<String, String>,
+        mode: CompareMode,
+        result: &Arc<Mutex<&mut Value>>,
     ) {
         // Does attrs have that key at all?
         if !attrs.contains_key(key) {
@@ -243,7 +439,7 @@ impl CompareGFF {
         let values2 = attrs.get_vec(key).unwrap();
 
         values2.par_iter().for_each(|value2|{
-            if !values.contains(&value2) {
+            if !values.contains(value2) {
                 let action = match mode {
                     CompareMode::Forward => "add",
                     _ => "remove",
@@ -260,7 +456,7 @@ impl CompareGFF {
             CompareMode::Forward => {}
             CompareMode::Reverse => {
                 values.par_iter().for_each(|value|{
-                    if !values2.contains(&value) {
+                    if !values2.contains(value) {
                         let j = json!({"action" : "add", "what" : "attribute" , "id" : id , "key":key , "value" : value });
                         result.lock().unwrap()["changes"]
                             .as_array_mut()
@@ -272,65 +468,117 @@ impl CompareGFF {
         }
     }
 
+    /// Returns the strand of a GFF element as a string, "." if not set.
+    fn strand_symbol(r: &bio::io::gff::Record) -> String {
+        match r.strand() {
+            Some(s) => s.strand_symbol().to_string(),
+            None => ".".to_string(),
+        }
+    }
+
     /// Compares the basic attributes (location, type etc.) of two GFF elements.
     fn compare_basics(
         &self,
         r1: &bio::io::gff::Record,
         r2: &bio::io::gff::Record,
         id: &str,
+    ) -> Vec<Value> {
+        Self::compare_columns(r1, r2, id)
+    }
+
+    /// Compares the columns of two GFF elements.
+    fn compare_columns(
+        r1: &bio::io::gff::Record,
+        r2: &bio::io::gff::Record,
+        id: &str,
     ) -> Vec<Value> {
         let mut changes: Vec<Value> = vec![];
         if r1.seqname() != r2.seqname() {
-            let j = json!({ "action" : "update" , "what" : "row" , "id" : id , "key" : "seqname" , "value" : r2.seqname() });
+            let j = json!({ "action" : "update" , "what" : "row" , "id" : id , "key" : "seqname" , "value" : r2.seqname() , "old_value" : r1.seqname() });
             changes.push(j);
         }
         if r1.source() != r2.source() {
-            let j = json!( { "action" : "update" , "what" : "row" , "id" : id , "key" : "source" , "value" : r2.source() });
+            let j = json!( { "action" : "update" , "what" : "row" , "id" : id , "key" : "source" , "value" : r2.source() , "old_value" : r1.source() });
             changes.push(j);
         }
         if r1.feature_type() != r2.feature_type() {
-            let j = json!( { "action" : "update" , "what" : "row" , "id" : id , "key" : "feature_type" , "value" : r2.feature_type() });
+            let j = json!( { "action" : "update" , "what" : "row" , "id" : id , "key" : "feature_type" , "value" : r2.feature_type() , "old_value" : r1.feature_type() });
             changes.push(j);
         }
         if r1.start() != r2.start() {
-            let j = json!( { "action" : "update" , "what" : "row" , "id" : id , "key" : "start" , "value" : r2.start().to_string() });
+            let j = json!( { "action" : "update" , "what" : "row" , "id" : id , "key" : "start" , "value" : r2.start().to_string() , "old_value" : r1.start().to_string() });
             changes.push(j);
         }
         if r1.end() != r2.end() {
-            let j = json!( { "action" : "update" , "what" : "row" , "id" : id , "key" : "end" , "value" : r2.end().to_string() });
+            let j = json!( { "action" : "update" , "what" : "row" , "id" : id , "key" : "end" , "value" : r2.end().to_string() , "old_value" : r1.end().to_string() });
             changes.push(j);
         }
         if r1.score() != r2.score() {
-            let j = json!( { "action" : "update" , "what" : "row" , "id" : id , "key" : "score" , "value" : r2.score() });
+            let j = json!( { "action" : "update" , "what" : "row" , "id" : id , "key" : "score" , "value" : r2.score() , "old_value" : r1.score() });
             changes.push(j);
         }
         if r1.strand() != r2.strand() {
-            let mut strand: String;
-            strand = ".".to_string();
-            let s = r2.strand();
-            if s.is_some() {
-                strand = s.unwrap().strand_symbol().to_string();
-            }
-            let j = json!( { "action" : "update" , "what" : "row" , "id" : id , "key" : "strand" , "value" : strand });
+            let j = json!( { "action" : "update" , "what" : "row" , "id" : id , "key" : "strand" , "value" : Self::strand_symbol(r2) , "old_value" : Self::strand_symbol(r1) });
             changes.push(j);
         }
         if r1.frame() != r2.frame() {
-            let j = json!( { "action" : "update" , "what" : "row" , "id" : id , "key" : "frame" , "value" : r2.frame() });
+            let j = json!( { "action" : "update" , "what" : "row" , "id" : id , "key" : "frame" , "value" : r2.frame() , "old_value" : r1.frame() });
             changes.push(j);
         }
         changes
     }
 
-    /// Compares the two loaded GFF files.
-    fn compare(&self, mode: CompareMode, result: &mut Value) -> Result<(), Box<dyn Error>> {
+    /// Compares two versions of a GFF element with the same ID.
+    fn compare_records(
+        id: &String,
+        r1: &bio::io::gff::Record,
+        r2: &bio::io::gff::Record,
+    ) -> Vec<Value> {
+        let mut changes = Self::compare_columns(r1, r2, id.as_str());
+        changes.append(&mut Self::compare_attribute_maps(
+            id,
+            r1.attributes(),
+            r2.attributes(),
+        ));
+        changes
+    }
+
+    /// Compares all attributes of two versions of a GFF element with the same ID.
+    fn compare_attribute_maps(
+        id: &String,
+        r1a: &MultiMap<String, String>,
+        r2a: &MultiMap<String, String>,
+    ) -> Vec<Value> {
+        let mut result = json!({ "changes": [] });
+        {
+            let result = Arc::new(Mutex::new(&mut result));
+            for (key, value) in r1a {
+                Self::compare_attribute_values(id, key, value, r2a, CompareMode::Forward, &result);
+            }
+
+            for (key, value) in r2a {
+                Self::compare_attribute_values(id, key, value, r1a, CompareMode::Reverse, &result);
+            }
+        }
+        match result["changes"].take() {
+            Value::Array(changes) => changes,
+            _ => vec![],
+        }
+    }
+
+    /// Compares the two loaded GFF files, passing the changes of each element to `emit` as they are found.
+    fn compare<F: Fn(Vec<Value>) + Sync>(
+        &self,
+        mode: CompareMode,
+        emit: F,
+    ) -> Result<(), Box<dyn Error>> {
         let (data1, data2) = match (&self.data1, &self.data2) {
             (Some(data1), Some(data2)) => match mode {
                 CompareMode::Forward => (data1, data2),
                 CompareMode::Reverse => (data2, data1),
             },
-            _ => return Err(From::from(format!("Both GFF sets need to be initialized"))),
+            _ => return Err(From::from("Both GFF sets need to be initialized")),
         };
-        let result = Arc::new(Mutex::new(result));
         data1.par_iter().for_each(|(id, r1)| {
             if data2.contains_key(id) {
                 match mode {
@@ -338,43 +586,21 @@ impl CompareGFF {
                     CompareMode::Reverse => return, // Already did that with CompareMode::Forward
                 }
                 let r2 = &data2[id];
-                self.compare_basics(&r1, r2, id.as_str())
-                    .par_iter()
-                    .for_each(|change| {
-                        result.lock().unwrap()["changes"]
-                            .as_array_mut()
-                            .unwrap()
-                            .push(change.to_owned())
-                    });
-
-                let r1a = r1.attributes();
-                let r2a = r2.attributes();
-                for (key, value) in r1a {
-                    self.compare_attributes(&id, key, value, r2a, CompareMode::Forward, &result);
-                }
-
-                for (key, value) in r2a {
-                    self.compare_attributes(&id, key, value, r1a, CompareMode::Reverse, &result);
+                let changes = Self::compare_records(id, r1, r2);
+                if !changes.is_empty() {
+                    emit(changes);
                 }
             } else {
                 match mode {
                     CompareMode::Forward => {
                         let mut o = json! ({"what":"row" , "action": "remove" , "id":id });
-                        let s = serde_json::to_string(&r1).unwrap();
-                        o["removed_element"] = serde_json::from_str(&s).unwrap();
-                        result.lock().unwrap()["changes"]
-                            .as_array_mut()
-                            .unwrap()
-                            .push(o);
+                        o["removed_element"] = Self::gff_to_json(r1);
+                        emit(vec![o]);
                     }
                     CompareMode::Reverse => {
                         let mut o = json! ({"what":"row" , "action": "add" , "id":id });
-                        let s = serde_json::to_string(&r1).unwrap();
-                        o["added_element"] = serde_json::from_str(&s).unwrap();
-                        result.lock().unwrap()["changes"]
-                            .as_array_mut()
-                            .unwrap()
-                            .push(o);
+                        o["added_element"] = Self::gff_to_json(r1);
+                        emit(vec![o]);
                     }
                 }
             }
@@ -382,74 +608,46 @@ impl CompareGFF {
         Ok(())
     }
 
-    fn get_root_parent_id(
-        &self,
-        data: &HashGFF,
-        id: &String,
-        seen: Option<HashSet<String>>,
-    ) -> Option<String> {
-        let mut seen: HashSet<String> = seen.unwrap_or(HashSet::new());
-        if seen.contains(id) {
-            return None; // circular ID chain, oh no
-        }
-        seen.insert(id.to_string());
-        match data.get(id) {
-            Some(element) => match element.attributes().get("Parent") {
-                Some(parent_id) => self.get_root_parent_id(data, parent_id, Some(seen)),
-                None => Some(id.to_string()),
-            },
-            None => None,
-        }
-    }
-
     fn infer_original_id_from_apollo(
         &self,
-        data1: &HashGFF,
-        data2: &HashGFF,
+        (data1, hierarchy1): (&HashGFF, &Hierarchy),
+        (data2, hierarchy2): (&HashGFF, &Hierarchy),
         apollo_element: &bio::io::gff::Record,
         issues: &mut Vec<String>,
-    ) -> Option<String> {
+    ) -> Option<(String, ApolloMatch)> {
         // Try orig_id
-        match apollo_element.attributes().get("orig_id") {
-            Some(orig_id) => {
-                return match data1.get(orig_id) {
-                    Some(_) => Some(orig_id.to_string()),
-                    None => {
-                        issues.push(format!(
-                            "Original ID '{}' given in Apollo GFF is not in full dataset!",
-                            orig_id
-                        ));
-                        None
-                    }
+        if let Some(orig_id) = apollo_element.attributes().get("orig_id") {
+            return match data1.get(orig_id) {
+                Some(_) => Some((orig_id.to_string(), ApolloMatch::OrigId)),
+                None => {
+                    issues.push(format!(
+                        "Original ID '{}' given in Apollo GFF is not in full dataset!",
+                        orig_id
+                    ));
+                    None
                 }
-            }
-            None => {}
+            };
         }
 
         // Find Apollo parent
         let apollo_id = apollo_element.attributes().get("ID")?;
-        let apollo_parent_id = self.get_root_parent_id(data2, apollo_id, None)?;
-        //let apollo_parent_element = data2.get(&apollo_parent_id)?;
+        let apollo_parent_id = hierarchy2.root_id(apollo_id)?;
 
         // Find any other Apollo element with that parent and an orig_id
-        let some_apollo_parent_id = Some(apollo_parent_id.to_owned());
-        let orig_parent_id = data2
-            .iter()
-            .filter(|(id, _element)| {
-                self.get_root_parent_id(data2, id, None) == some_apollo_parent_id
-            }) // Same Apollo parent
-            .filter_map(|(_id, element)| element.attributes().get("orig_id")) // with orig_id
-            .map(|s| s.to_string())
-            .filter(|orig_id| data1.contains_key(orig_id)) // with orig_id that exists in original dataset
-            .filter_map(|orig_id| self.get_root_parent_id(data1, &orig_id, None)) // get that original root parent
+        let orig_parent_id = hierarchy2
+            .members(apollo_parent_id)
+            .iter() // Same Apollo parent
+            .filter_map(|id| data2.get(id)?.attributes().get("orig_id")) // with orig_id
+            .filter(|orig_id| data1.contains_key(*orig_id)) // with orig_id that exists in original dataset
+            .filter_map(|orig_id| hierarchy1.root_id(orig_id)) // get that original root parent
             .nth(0)?;
 
         // Get all (sub)children on that parent in the original
-        let some_orig_parent_id = Some(orig_parent_id);
-        let all_children_orig: HashGFF = data1
-            .iter()
+        let all_children_orig: HashGFF = hierarchy1
+            .members(orig_parent_id)
+            .iter() // Same root parent
+            .filter_map(|id| Some((id, data1.get(id)?)))
             .filter(|(_id, data)| data.seqname() == apollo_element.seqname()) // Same chromosome
-            .filter(|(id, _data)| self.get_root_parent_id(data1, id, None) == some_orig_parent_id) // Same root parent
             .map(|(id, data)| (id.to_owned(), data.to_owned()))
             .collect();
 
@@ -462,49 +660,294 @@ impl CompareGFF {
 
         // Found one element with the same type and (root) parent in the original data, using that one
         if same_type.len() == 1 {
-            return Some(
-                same_type
-                    .iter()
-                    .map(|(id, _data)| id.to_owned())
-                    .nth(0)
-                    .unwrap(),
-            );
+            return Some((
+                same_type.keys().map(|id| id.to_owned()).nth(0).unwrap(),
+                ApolloMatch::Sibling,
+            ));
+        }
+
+        // Try location, preferably of an element with the same type
+        match same_type.is_empty() {
+            true => Self::infer_original_id_by_location(apollo_element, &all_children_orig),
+            false => Self::infer_original_id_by_location(apollo_element, &same_type),
+        }
+    }
+
+    /// Returns the overlap of two GFF elements, as a fraction of the longer one. 0.0 if they do not overlap.
+    fn reciprocal_overlap(r1: &bio::io::gff::Record, r2: &bio::io::gff::Record) -> f64 {
+        let start = *r1.start().max(r2.start());
+        let end = *r1.end().min(r2.end());
+        if end < start {
+            return 0.0;
+        }
+        let len1 = r1.end().saturating_sub(*r1.start()) + 1;
+        let len2 = r2.end().saturating_sub(*r2.start()) + 1;
+        (end - start + 1) as f64 / len1.max(len2) as f64
+    }
+
+    /// Finds the original element with the best reciprocal overlap on the same seqname/strand,
+    /// tie-broken by type and phase.
+    /// Several Apollo elements may find the same original; `compare_apollo` keeps only the best one.
+    fn infer_original_id_by_location(
+        apollo_element: &bio::io::gff::Record,
+        candidates: &HashGFF,
+    ) -> Option<(String, ApolloMatch)> {
+        candidates
+            .iter()
+            .filter(|(_id, data)| data.seqname() == apollo_element.seqname())
+            .filter(|(_id, data)| data.strand() == apollo_element.strand())
+            .map(|(id, data)| {
+                (
+                    Self::reciprocal_overlap(data, apollo_element),
+                    data.feature_type() == apollo_element.feature_type(),
+                    data.frame() == apollo_element.frame(),
+                    id,
+                )
+            })
+            .filter(|(overlap, _, _, _)| *overlap > 0.0)
+            .max_by(|a, b| {
+                a.0.partial_cmp(&b.0)
+                    .unwrap_or(std::cmp::Ordering::Equal)
+                    .then(a.1.cmp(&b.1))
+                    .then(a.2.cmp(&b.2))
+                    .then(b.3.cmp(a.3)) // Lowest ID, for reproducible results
+            })
+            .map(|(overlap, same_type, _, id)| {
+                (id.to_owned(), ApolloMatch::Location(overlap, same_type))
+            })
+    }
+
+    /// Checks if an attribute key is in a list of keys. A trailing `*` in the list matches any suffix.
+    fn attribute_key_matches(key: &str, keys: &[String]) -> bool {
+        keys.iter().any(|k| match k.strip_suffix('*') {
+            Some(prefix) => key.starts_with(prefix),
+            None => key == k,
+        })
+    }
+
+    /// Checks if the Apollo element has a name generated by Apollo from its ID (e.g. `ID-exon`).
+    fn has_apollo_generated_name(apollo_element: &bio::io::gff::Record) -> bool {
+        let attributes = apollo_element.attributes();
+        match (attributes.get("ID"), attributes.get("Name")) {
+            (Some(id), Some(name)) => name.starts_with(id.as_str()),
+            _ => false,
         }
+    }
 
-        // TODO try location?
+    /// Returns the attributes of an element that are compared in Apollo diffs.
+    /// ID and Parent are never included, as they are handled separately.
+    fn apollo_attributes(
+        &self,
+        element: &bio::io::gff::Record,
+        ignore_name: bool,
+    ) -> MultiMap<String, String> {
+        element
+            .attributes()
+            .iter_all()
+            .filter(|(key, _values)| *key != "ID" && *key != "Parent")
+            .filter(|(key, _values)| !(ignore_name && *key == "Name"))
+            .filter(|(key, _values)| {
+                !Self::attribute_key_matches(key, &self.apollo_ignored_attributes)
+            })
+            .filter(|(key, _values)| match &self.apollo_compared_attributes {
+                Some(keys) => Self::attribute_key_matches(key, keys),
+                None => true,
+            })
+            .flat_map(|(key, values)| values.iter().map(move |v| (key.to_owned(), v.to_owned())))
+            .collect()
+    }
 
-        None
+    /// Compares the attributes of an original element and an Apollo element.
+    /// Only the attribute keys of the Apollo element are compared, as Apollo drops many attributes
+    /// (e.g. Dbxref) on export; original attributes with other keys are kept.
+    fn compare_apollo_attributes(
+        &self,
+        original_id: &String,
+        original_element: &bio::io::gff::Record,
+        apollo_element: &bio::io::gff::Record,
+    ) -> Vec<Value> {
+        // Names generated by Apollo do not replace original names
+        let ignore_name = Self::has_apollo_generated_name(apollo_element);
+        let original_attributes = self.apollo_attributes(original_element, ignore_name);
+        let apollo_attributes = self.apollo_attributes(apollo_element, ignore_name);
+        let mut result = json!({ "changes": [] });
+        {
+            let result = Arc::new(Mutex::new(&mut result));
+            for (key, values) in apollo_attributes.iter_all() {
+                self.compare_attributes(
+                    original_id,
+                    key,
+                    values,
+                    &original_attributes,
+                    CompareMode::Reverse,
+                    &result,
+                );
+            }
+        }
+        let mut seen: HashSet<(String, String, String)> = HashSet::new();
+        match result["changes"].take() {
+            Value::Array(changes) => changes
+                .into_iter()
+                .filter(|change| {
+                    let text = |key: &str| change[key].as_str().unwrap_or("").to_string();
+                    seen.insert((text("id"), text("key"), text("value")))
+                })
+                .collect(),
+            _ => vec![],
+        }
+    }
+
+    /// Creates a new element from an Apollo element, using the (original or new) IDs for itself and its parents.
+    fn apollo_new_element(
+        &self,
+        apollo_element: &bio::io::gff::Record,
+        original_ids: &HashMap<String, String>,
+    ) -> bio::io::gff::Record {
+        let ignore_name = Self::has_apollo_generated_name(apollo_element);
+        let mut ret = apollo_element.clone();
+        let map_id = |id: &String| original_ids.get(id).unwrap_or(id).to_owned();
+        let mut attributes = self.apollo_attributes(apollo_element, ignore_name);
+        if let Some(ids) = apollo_element.attributes().get_vec("ID") {
+            attributes.insert_many("ID".to_string(), ids.iter().map(map_id));
+        }
+        if let Some(ids) = apollo_element.attributes().get_vec("Parent") {
+            attributes.insert_many("Parent".to_string(), ids.iter().map(map_id));
+        }
+        *ret.attributes_mut() = attributes;
+        ret
     }
 
     /// data1 is "full" GFF, data2 is Apollo GFF
     fn compare_apollo(&self) -> Result<Value, Box<dyn Error>> {
-        let (data1, data2) = match (&self.data1, &self.data2) {
-            (Some(data1), Some(data2)) => (data1, data2),
-            _ => return Err(From::from(format!("Both GFF sets need to be initialized"))),
-        };
+        let (data1, hierarchy1, data2, hierarchy2) =
+            match (&self.data1, &self.hierarchy1, &self.data2, &self.hierarchy2) {
+                (Some(data1), Some(h1), Some(data2), Some(h2)) => (data1, h1, data2, h2),
+                _ => return Err(From::from("Both GFF sets need to be initialized")),
+            };
         let mut issues: Vec<String> = self.issues.clone();
         let mut changes: Vec<Value> = vec![];
 
-        let _re = Regex::new(r"-\d+$").unwrap();
-
-        for (_id, apollo_element) in data2 {
-            let _attrs = apollo_element.attributes();
-            let original_id = match self.infer_original_id_from_apollo(
-                data1,
-                data2,
-                &apollo_element,
+        // Map Apollo IDs to original IDs
+        let mut apollo_ids: Vec<&String> = data2.keys().collect();
+        apollo_ids.sort();
+        let mut original_ids: HashMap<String, String> = HashMap::new();
+        let mut matches: HashMap<&String, ApolloMatch> = HashMap::new();
+        let mut new_ids: Vec<&String> = vec![];
+        for apollo_id in &apollo_ids {
+            let apollo_element = &data2[*apollo_id];
+            match self.infer_original_id_from_apollo(
+                (data1, hierarchy1),
+                (data2, hierarchy2),
+                apollo_element,
                 &mut issues,
             ) {
-                Some(id) => id,
-                None => {
-                    issues.push(format!("No original ID found for {:?}", apollo_element));
-                    continue;
+                Some((id, apollo_match)) => {
+                    original_ids.insert(apollo_id.to_string(), id);
+                    matches.insert(apollo_id, apollo_match);
                 }
+                None => new_ids.push(apollo_id),
+            }
+        }
+
+        // Map each original element to one Apollo element only, preferring orig_id, then the best overlap;
+        // the others (e.g. the pieces of a split exon) are new elements
+        let mut claims: HashMap<String, Vec<&String>> = HashMap::new();
+        for apollo_id in &apollo_ids {
+            if let Some(id) = original_ids.get(*apollo_id) {
+                claims.entry(id.to_owned()).or_default().push(apollo_id);
+            }
+        }
+        for (id, mut claimants) in claims {
+            if claimants.len() < 2 {
+                continue;
+            }
+            let rank = |apollo_id: &String| {
+                (
+                    matches[apollo_id] == ApolloMatch::OrigId,
+                    Self::reciprocal_overlap(&data1[&id], &data2[apollo_id]),
+                )
             };
-            let original_parent_id = match data1.get(&original_id) {
-                Some(e) => e.attributes().get("Parent"),
-                None => None,
+            claimants.sort_by(|a, b| {
+                let (rank_a, rank_b) = (rank(a), rank(b));
+                rank_b
+                    .0
+                    .cmp(&rank_a.0)
+                    .then(
+                        rank_b
+                            .1
+                            .partial_cmp(&rank_a.1)
+                            .unwrap_or(std::cmp::Ordering::Equal),
+                    )
+                    .then(a.cmp(b))
+            });
+            for apollo_id in claimants.into_iter().skip(1) {
+                if matches[apollo_id] == ApolloMatch::OrigId {
+                    continue; // Explicitly mapped by Apollo
+                }
+                original_ids.remove(apollo_id);
+                matches.remove(apollo_id);
+                new_ids.push(apollo_id);
+            }
+        }
+
+        // Generate IDs for new Apollo elements, parents first
+        new_ids.sort_by_cached_key(|apollo_id| {
+            let e = &data2[*apollo_id];
+            (
+                e.seqname().to_string(),
+                *e.start(),
+                hierarchy2.depth(apollo_id).unwrap_or(0),
+                apollo_id.to_string(),
+            )
+        });
+        let mut number: usize = 0;
+        for apollo_id in &new_ids {
+            let new_id = loop {
+                number += 1;
+                let new_id = format!("{}{:06}", self.apollo_id_prefix, number);
+                if !data1.contains_key(&new_id) {
+                    break new_id;
+                }
             };
+            original_ids.insert(apollo_id.to_string(), new_id);
+        }
+        for apollo_id in &new_ids {
+            let new_element = self.apollo_new_element(&data2[*apollo_id], &original_ids);
+            let new_id = &original_ids[*apollo_id];
+            changes.push(json! ({"what":"row" , "action": "add" , "id":new_id , "added_element":Self::gff_to_json(&new_element) }));
+        }
+        let new_ids: HashSet<&String> = new_ids.drain(..).collect();
+
+        // Remove original elements missing in Apollo, if their root parent is in Apollo
+        let mapped_ids: HashSet<&String> = apollo_ids
+            .iter()
+            .filter(|apollo_id| !new_ids.contains(*apollo_id))
+            .filter_map(|apollo_id| original_ids.get(*apollo_id))
+            .collect();
+        let root_ids: HashSet<&String> = mapped_ids
+            .iter()
+            .filter_map(|id| hierarchy1.root_id(id))
+            .collect();
+        let mut removed_ids: Vec<&String> = data1
+            .par_iter()
+            .filter(|(id, _element)| !mapped_ids.contains(id))
+            .filter(|(id, _element)| {
+                let root_id = hierarchy1.root_id(id);
+                root_id.is_some_and(|root_id| root_ids.contains(root_id))
+            })
+            .map(|(id, _element)| id)
+            .collect();
+        removed_ids.sort();
+        for id in removed_ids {
+            changes.push(json! ({"what":"row" , "action": "remove" , "id":id , "removed_element":Self::gff_to_json(&data1[id]) }));
+        }
+
+        for apollo_id in apollo_ids {
+            if new_ids.contains(apollo_id) {
+                continue;
+            }
+            let apollo_element = &data2[apollo_id];
+            let original_id = original_ids[apollo_id].to_owned();
             let original_element = match data1.get(&original_id) {
                 Some(e) => e,
                 None => {
@@ -513,31 +956,34 @@ impl CompareGFF {
                 }
             };
 
-            // Add/remove/change parent ID
-            match (
-                original_parent_id,
-                original_element.attributes().get("Parent"),
-            ) {
-                (Some(apollo_opid), Some(original_opid)) => {
-                    if *apollo_opid != *original_opid {
-                        let j = json!({ "action":"update" , "what":"attribute" , "id" : original_id , "key":"Parent" , "value" : apollo_opid } );
-                        changes.push(j);
-                        let j = json!({ "action":"remove" , "what":"attribute" , "id" : original_id , "key":"Parent" , "value" : original_opid } );
-                        changes.push(j);
-                    }
-                }
-                (Some(apollo_opid), None) => {
-                    let j = json!({ "action":"add" , "what":"attribute" , "id" : original_id , "key":"Parent" , "value" : apollo_opid } );
+            // Add/remove/change parent ID, with Apollo parent IDs mapped to original IDs
+            let apollo_attributes = apollo_element.attributes();
+            let apollo_parent_ids: Vec<String> = match apollo_attributes.get_vec("Parent") {
+                Some(ids) => ids
+                    .iter()
+                    .map(|id| original_ids.get(id).unwrap_or(id).to_owned())
+                    .collect(),
+                None => vec![],
+            };
+            let original_attributes = original_element.attributes();
+            let original_parent_ids: Vec<String> = match original_attributes.get_vec("Parent") {
+                Some(ids) => ids.to_owned(),
+                None => vec![],
+            };
+            for original_parent_id in &original_parent_ids {
+                if !apollo_parent_ids.contains(original_parent_id) {
+                    let j = json!({ "action":"remove" , "what":"attribute" , "id" : original_id , "key":"Parent" , "value" : original_parent_id } );
                     changes.push(j);
                 }
-                (None, Some(original_opid)) => {
-                    let _j = json!({ "action":"remove" , "what":"attribute" , "id" : original_id , "key":"Parent" , "value" : original_opid } );
-                    //changes.push(j); // IGNORE THIS
+            }
+            for apollo_parent_id in &apollo_parent_ids {
+                if !original_parent_ids.contains(apollo_parent_id) {
+                    let j = json!({ "action":"add" , "what":"attribute" , "id" : original_id , "key":"Parent" , "value" : apollo_parent_id } );
+                    changes.push(j);
                 }
-                (None, None) => {}
             }
 
-            self.compare_basics(&original_element, &apollo_element, original_id.as_str())
+            self.compare_basics(original_element, apollo_element, original_id.as_str())
                 .drain(..)
                 .filter(|change| {
                     match (
@@ -550,14 +996,39 @@ impl CompareGFF {
                     }
                 })
                 .for_each(|change| changes.push(change));
+
+            changes.append(&mut self.compare_apollo_attributes(
+                &original_id,
+                original_element,
+                apollo_element,
+            ));
         }
 
+        let reconciliation =
+            Self::apollo_reconciliation(data2, hierarchy2, &original_ids, &matches);
         Ok(match self.record_issues {
-            true => json!({"changes": changes, "issues": issues}),
-            false => json!({ "changes": changes }),
+            true => {
+                json!({"format_version": DIFF_FORMAT_VERSION, "changes": changes, "reconciliation": reconciliation, "issues": issues})
+            }
+            false => {
+                json!({ "format_version": DIFF_FORMAT_VERSION, "changes": changes, "reconciliation": reconciliation })
+            }
         })
     }
 
+    fn gff_to_json(r: &bio::io::gff::Record) -> Value {
+        let s = serde_json::to_string(r).unwrap();
+        serde_json::from_str(&s).unwrap()
+    }
+
+    /// Returns the score column of a record as in the file (`.` if empty); `score()` only parses integers.
+    pub(crate) fn score_text(r: &bio::io::gff::Record) -> String {
+        match Self::gff_to_json(r)["score"].as_str() {
+            Some(score) if !score.is_empty() => score.to_string(),
+            _ => ".".to_string(),
+        }
+    }
+
     fn gff_from_json(j: &Value) -> Result<bio::io::gff::Record, String> {
         let mut ret = bio::io::gff::Record::new();
         Self::apply_diff_row_update(&json!({"key":"seqname","value":&j["seqname"]}), &mut ret)
@@ -577,28 +1048,22 @@ impl CompareGFF {
         Self::apply_diff_row_update(&json!({"key":"frame","value":&j["frame"]}), &mut ret).ignore();
 
         // Attributes
-        match j["attributes"].as_object() {
-            Some(attributes) => {
-                attributes.iter().for_each(|(key, values)| {
-                    match values.as_array() {
-                        Some(values) => {
-                            values
-                                .iter()
-                                .filter_map(|value| value.as_str())
-                                .for_each(|value| {
-                                    Self::apply_diff_attribute_add(
-                                        &mut ret,
-                                        key.to_string(),
-                                        value.to_string(),
-                                    )
-                                    .ignore();
-                                })
-                        }
-                        None => {} // No values?!?
-                    }
-                });
-            }
-            None => {} // No attributes
+        if let Some(attributes) = j["attributes"].as_object() {
+            attributes.iter().for_each(|(key, values)| {
+                if let Some(values) = values.as_array() {
+                    values
+                        .iter()
+                        .filter_map(|value| value.as_str())
+                        .for_each(|value| {
+                            Self::apply_diff_attribute_add(
+                                &mut ret,
+                                key.to_string(),
+                                value.to_string(),
+                            )
+                            .ignore();
+                        })
+                }
+            });
         }
 
         Ok(ret)
@@ -609,7 +1074,7 @@ impl CompareGFF {
             Some(id) => {
                 data.remove(id);
             }
-            None => return Err(format!("apply_diff_row_remove: add row, but no id set")),
+            None => return Err("apply_diff_row_remove: add row, but no id set".to_string()),
         }
         Ok(())
     }
@@ -673,7 +1138,7 @@ impl CompareGFF {
     }
 
     fn apply_diff_rows(
-        changes: &Vec<Value>,
+        changes: &[Value],
         data: &mut HashGFF,
         action: &str,
     ) -> Result<(), Box<dyn Error>> {
@@ -690,10 +1155,7 @@ impl CompareGFF {
                 )),
                 _ => Err(format!("apply_diff_rows: No action in {:?}", change)),
             })
-            .filter_map(|r| match r {
-                Ok(_) => None,
-                Err(e) => Some(e),
-            })
+            .filter_map(|r| r.err())
             .nth(0)
         {
             Some(err) => Err(From::from(err)),
@@ -728,7 +1190,7 @@ impl CompareGFF {
     }
 
     fn apply_diff_attributes(
-        changes: &Vec<Value>,
+        changes: &[Value],
         data: &mut HashGFF,
         action: &str,
     ) -> Result<(), Box<dyn Error>> {
@@ -770,10 +1232,7 @@ impl CompareGFF {
                     _ => Err(format!("apply_diff_attributes: No action in {:?}", change)),
                 }
             })
-            .filter_map(|r| match r {
-                Ok(_) => None,
-                Err(e) => Some(e),
-            })
+            .filter_map(|r| r.err())
             .nth(0)
         {
             Some(err) => Err(From::from(err)),
@@ -781,24 +1240,105 @@ impl CompareGFF {
         }
     }
 
+    /// Inverts a single change, so it undoes the original change.
+    fn invert_change(change: &Value) -> Result<Value, String> {
+        let mut ret = change.to_owned();
+        match (change["what"].as_str(), change["action"].as_str()) {
+            (Some("row"), Some("add")) => {
+                ret["action"] = json!("remove");
+                ret["removed_element"] = change["added_element"].to_owned();
+                ret.as_object_mut().map(|o| o.remove("added_element"));
+            }
+            (Some("row"), Some("remove")) => {
+                ret["action"] = json!("add");
+                ret["added_element"] = change["removed_element"].to_owned();
+                ret.as_object_mut().map(|o| o.remove("removed_element"));
+            }
+            (_, Some("update")) => {
+                if change.get("old_value").is_none() {
+                    return Err(format!("invert_change: No old_value in {}", change));
+                }
+                ret["value"] = change["old_value"].to_owned();
+                ret["old_value"] = change["value"].to_owned();
+            }
+            (Some("attribute"), Some("add")) => ret["action"] = json!("remove"),
+            (Some("attribute"), Some("remove")) => ret["action"] = json!("add"),
+            _ => return Err(format!("invert_change: Cannot invert {}", change)),
+        }
+        Ok(ret)
+    }
+
+    /// Inverts a diff, so that applying it turns the second GFF file into the first one.
+    pub fn invert_diff(diff: &Value) -> Result<Value, Box<dyn Error>> {
+        let changes = match diff["changes"].as_array() {
+            Some(changes) => changes,
+            None => return Err(From::from("No changes in diff")),
+        };
+        let changes: Vec<Value> = changes
+            .iter()
+            .map(Self::invert_change)
+            .collect::<Result<Vec<Value>, String>>()?;
+        let mut ret = diff.to_owned();
+        ret["changes"] = json!(changes);
+        Ok(ret)
+    }
+
     /// Applies the given diff to the data loaded into the gff 1 slot.
+    /// The diff is checked against the diff format schema first.
     pub fn apply_diff(&mut self, diff: &Value) -> Result<&HashGFF, Box<dyn Error>> {
+        Self::validate_diff(diff)?;
         let changes = match diff["changes"].as_array() {
             Some(changes) => changes,
-            None => return Err(From::from(format!("No changes in diff"))),
+            None => return Err(From::from("No changes in diff")),
         };
         let data = match self.data1.as_mut() {
             Some(data) => data,
-            _ => return Err(From::from(format!("GFF set 1 needs to be initialized"))),
+            _ => return Err(From::from("GFF set 1 needs to be initialized")),
+        };
+        Self::apply_changes(changes, data)?;
+        self.hierarchy1 = Some(Hierarchy::new(data));
+        Ok(data)
+    }
+
+    /// Applies the given diff backwards to the data loaded into the gff 2 slot.
+    /// The diff is checked against the diff format schema first.
+    pub fn apply_diff_reverse(&mut self, diff: &Value) -> Result<&HashGFF, Box<dyn Error>> {
+        Self::validate_diff(diff)?;
+        let inverted = Self::invert_diff(diff)?;
+        let changes = match inverted["changes"].as_array() {
+            Some(changes) => changes,
+            None => return Err(From::from("No changes in diff")),
+        };
+        let data = match self.data2.as_mut() {
+            Some(data) => data,
+            _ => return Err(From::from("GFF set 2 needs to be initialized")),
         };
-        Self::apply_diff_rows(&changes, data, "remove")?;
-        Self::apply_diff_rows(&changes, data, "add")?;
-        Self::apply_diff_rows(&changes, data, "update")?;
-        Self::apply_diff_attributes(&changes, data, "remove")?;
-        Self::apply_diff_attributes(&changes, data, "add")?;
-        Self::apply_diff_attributes(&changes, data, "update")?;
+        Self::apply_changes(changes, data)?;
+        self.hierarchy2 = Some(Hierarchy::new(data));
         Ok(data)
     }
+
+    fn apply_changes(changes: &[Value], data: &mut HashGFF) -> Result<(), Box<dyn Error>> {
+        Self::apply_diff_rows(changes, data, "remove")?;
+        Self::apply_diff_rows(changes, data, "add")?;
+        Self::apply_diff_rows(changes, data, "update")?;
+        Self::apply_diff_attributes(changes, data, "remove")?;
+        Self::apply_diff_attributes(changes, data, "add")?;
+        Self::apply_diff_attributes(changes, data, "update")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl CompareGFF {
+    /// Loads two files of a test directory into the base and target data sets.
+    pub(crate) fn load_test_files(&mut self, dir: &str, base: &str, target: &str) {
+        let path = |file: &str| format!("test/{}/{}", dir, file);
+        self.load(GffSource::file(path(base)), DataSet::Base)
+            .unwrap();
+        self.load(GffSource::file(path(target)), DataSet::Target)
+            .unwrap();
+    }
 }
 
 #[cfg(test)]
@@ -808,15 +1348,15 @@ mod tests {
 
     /// This computes a diff between two test GFF files, then compares it with the expected result (also a file).
     /// Then, it applies the diff to the original GFF file (#1), and diffs the modified GFF to the #2 GFF.
-    /// This should yield no changes
+    /// This should yield no changes. Finally, it applies the diff backwards to the #2 GFF, which should yield the #1 GFF.
     fn compare_expected(dir: &str) {
         let gff_file1 = format!("test/{}/core.gff", dir);
         let gff_file2 = format!("test/{}/cap.gff", dir);
         let expected_file = format!("test/{}/expected.json", dir);
         let mut cg = CompareGFF::new(); //_from_files(gff_file1, gff_file2).unwrap();
         cg.record_issues(true);
-        cg.load_gff(gff_file1, 1).unwrap();
-        cg.load_gff(gff_file2, 2).unwrap();
+        cg.load_gff(&gff_file1, 1).unwrap();
+        cg.load_gff(&gff_file2, 2).unwrap();
         let mut diff = cg.diff().unwrap();
         let expected = fs::read_to_string(expected_file).unwrap();
         let mut expected: Value = serde_json::from_str(&expected).unwrap();
@@ -824,10 +1364,34 @@ mod tests {
         CompareGFF::sort_comparison(&mut expected);
         assert_eq!(diff["changes"], expected["changes"]);
 
-        // Apply diff to data2
+        // Apply diff to data1
         cg.apply_diff(&diff).unwrap();
-        let diff = cg.diff().unwrap();
-        assert_eq!(diff["changes"].as_array().unwrap().len(), 0);
+        let new_diff = cg.diff().unwrap();
+        assert_eq!(new_diff["changes"].as_array().unwrap().len(), 0);
+
+        // Apply diff backwards to data2
+        let mut cg = CompareGFF::new();
+        cg.record_issues(true);
+        cg.load_gff(&gff_file1, 1).unwrap();
+        cg.load_gff(&gff_file2, 2).unwrap();
+        cg.apply_diff_reverse(&diff).unwrap();
+        let new_diff = cg.diff().unwrap();
+        assert_eq!(new_diff["changes"].as_array().unwrap().len(), 0);
+    }
+
+    /// This computes an Apollo diff between a full GFF file and an Apollo GFF file, then compares it with the expected result.
+    fn compare_expected_apollo(dir: &str) -> Value {
+        let mut cg = CompareGFF::new();
+        cg.record_issues(true);
+        cg.load_gff(format!("test/{}/core.gff", dir), 1).unwrap();
+        cg.load_gff(format!("test/{}/apollo.gff", dir), 2).unwrap();
+        let mut diff = cg.diff_apollo().unwrap();
+        let expected = fs::read_to_string(format!("test/{}/expected.json", dir)).unwrap();
+        let mut expected: Value = serde_json::from_str(&expected).unwrap();
+        CompareGFF::sort_comparison(&mut diff);
+        CompareGFF::sort_comparison(&mut expected);
+        assert_eq!(diff["changes"], expected["changes"]);
+        diff
     }
 
     #[test]
@@ -856,6 +1420,26 @@ mod tests {
         assert_eq!(**result.lock().unwrap(), expected);
     }
 
+    #[test]
+    fn invert_diff() {
+        let diff = json!({"changes":[
+            {"action":"update","what":"row","id":"E1","key":"end","value":"1200","old_value":"900"},
+            {"action":"add","what":"attribute","id":"E1","key":"Note","value":"x"},
+            {"action":"add","what":"row","id":"E2","added_element":{"seqname":"chr1"}}
+        ]});
+        let expected = json!({"changes":[
+            {"action":"update","what":"row","id":"E1","key":"end","value":"900","old_value":"1200"},
+            {"action":"remove","what":"attribute","id":"E1","key":"Note","value":"x"},
+            {"action":"remove","what":"row","id":"E2","removed_element":{"seqname":"chr1"}}
+        ]});
+        let inverted = CompareGFF::invert_diff(&diff).unwrap();
+        assert_eq!(inverted, expected);
+        assert_eq!(CompareGFF::invert_diff(&inverted).unwrap(), diff);
+
+        let no_old_value = json!({"changes":[{"action":"update","what":"row","id":"E1","key":"end","value":"1200"}]});
+        assert!(CompareGFF::invert_diff(&no_old_value).is_err());
+    }
+
     #[test]
     fn added_exon() {
         compare_expected("added_exon");
@@ -866,6 +1450,72 @@ mod tests {
         compare_expected("alter_exon");
     }
 
+    #[test]
+    fn apollo_location() {
+        let diff = compare_expected_apollo("apollo_location");
+        assert_eq!(diff["issues"], json!([]));
+    }
+
+    #[test]
+    fn apollo_new_deleted() {
+        compare_expected_apollo("apollo_new_deleted");
+    }
+
+    #[test]
+    fn apollo_split_exon() {
+        compare_expected_apollo("apollo_split_exon");
+    }
+
+    #[test]
+    fn apollo_attributes() {
+        compare_expected_apollo("apollo_attributes");
+    }
+
+    #[test]
+    fn apollo_compared_attributes() {
+        let mut cg = CompareGFF::new();
+        cg.apollo_compared_attributes(Some(vec!["Note".to_string()]));
+        cg.load_gff("test/apollo_attributes/core.gff", 1).unwrap();
+        cg.load_gff("test/apollo_attributes/apollo.gff", 2).unwrap();
+        let diff = cg.diff_apollo().unwrap();
+        assert_eq!(
+            diff["changes"],
+            json!([{"action":"add","what":"attribute","id":"M0001","key":"Note","value":"Reviewed"}])
+        );
+    }
+
+    #[test]
+    fn apollo_exon_move() {
+        compare_expected_apollo("apollo_exon_move");
+
+        // The diff applies to the full GFF
+        let mut cg = CompareGFF::new();
+        cg.load_gff("test/apollo_exon_move/core.gff", 1).unwrap();
+        cg.load_gff("test/apollo_exon_move/apollo.gff", 2).unwrap();
+        let diff = cg.diff_apollo().unwrap();
+        let data1 = cg.apply_diff(&diff).unwrap();
+        let parents = data1["E0003"].attributes().get_vec("Parent").unwrap();
+        assert_eq!(*parents, vec!["M0002".to_string()]);
+    }
+
+    #[test]
+    fn apollo_id_prefix() {
+        let mut cg = CompareGFF::new();
+        cg.apollo_id_prefix("NEW");
+        cg.load_gff("test/apollo_new_deleted/core.gff", 1).unwrap();
+        cg.load_gff("test/apollo_new_deleted/apollo.gff", 2)
+            .unwrap();
+        let diff = cg.diff_apollo().unwrap();
+        let added: Vec<&str> = diff["changes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|change| change["action"] == "add")
+            .filter_map(|change| change["id"].as_str())
+            .collect();
+        assert_eq!(added, vec!["NEW000001", "NEW000002", "NEW000003"]);
+    }
+
     #[test]
     fn gene_in_intron() {
         compare_expected("gene_in_intron");
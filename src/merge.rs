@@ -0,0 +1,343 @@
+use crate::{CompareGFF, DataSet, GffSource, DIFF_FORMAT_VERSION};
+use serde_json::value::Value;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::error::Error;
+
+/// Which side of a three-way merge a change comes from.
+#[derive(Clone, Copy)]
+enum MergeSide {
+    Ours,
+    Theirs,
+}
+
+impl MergeSide {
+    fn key(&self) -> &'static str {
+        match self {
+            MergeSide::Ours => "ours",
+            MergeSide::Theirs => "theirs",
+        }
+    }
+}
+
+/// The part of a feature a change touches; also the key of a conflict.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub(crate) enum MergeSlot {
+    /// An attribute key of a row, or one value of it.
+    Attribute {
+        id: String,
+        key: String,
+        value: Option<String>,
+    },
+    /// A change that is not a row or attribute change, as JSON.
+    Other(String),
+    /// A whole row, or one column of it.
+    Row { id: String, key: Option<String> },
+}
+
+impl MergeSlot {
+    /// Returns the attribute key or column name, if the slot has one.
+    fn key(&self) -> Option<&str> {
+        match self {
+            MergeSlot::Attribute { key, .. } => Some(key),
+            MergeSlot::Row { key, .. } => key.as_deref(),
+            MergeSlot::Other(_) => None,
+        }
+    }
+}
+
+/// The conflicts of a merge, by conflict key.
+#[derive(Default)]
+struct MergeConflicts {
+    conflicts: BTreeMap<MergeSlot, Value>,
+    /// The conflict key, side, and change of every change added
+    added: HashSet<(MergeSlot, &'static str, String)>,
+}
+
+impl MergeConflicts {
+    fn add(&mut self, conflict_key: MergeSlot, change: &Value, side: MergeSide) {
+        if !self
+            .added
+            .insert((conflict_key.clone(), side.key(), change.to_string()))
+        {
+            return;
+        }
+        let conflict = self
+            .conflicts
+            .entry(conflict_key)
+            .or_insert_with_key(|conflict_key| {
+                let mut conflict =
+                    json!({"id":change["id"],"what":change["what"],"ours":[],"theirs":[]});
+                // Conflicts on a whole row have no key
+                if conflict_key.key().is_some() {
+                    conflict["key"] = change["key"].to_owned();
+                }
+                conflict
+            });
+        conflict[side.key()]
+            .as_array_mut()
+            .unwrap()
+            .push(change.to_owned());
+    }
+
+    fn into_values(self) -> Vec<Value> {
+        self.conflicts.into_values().collect()
+    }
+}
+
+impl CompareGFF {
+    /// Returns the part of a feature a change touches.
+    /// Two changes with the same slot cannot both be applied, unless they are identical.
+    pub(crate) fn merge_slot(change: &Value) -> MergeSlot {
+        let id = change["id"].as_str().unwrap_or("").to_string();
+        let key = change["key"].as_str().unwrap_or("").to_string();
+        match (change["what"].as_str(), change["action"].as_str()) {
+            (Some("row"), Some("update")) => MergeSlot::Row { id, key: Some(key) },
+            (Some("row"), _) => MergeSlot::Row { id, key: None },
+            (Some("attribute"), _) => MergeSlot::Attribute {
+                id,
+                key,
+                value: Some(change["value"].as_str().unwrap_or("").to_string()),
+            },
+            _ => MergeSlot::Other(change.to_string()),
+        }
+    }
+
+    /// Returns the IDs of all rows removed by a list of changes.
+    fn merge_removed_ids(changes: &[Value]) -> HashSet<String> {
+        changes
+            .iter()
+            .filter(|change| change["what"].as_str() == Some("row"))
+            .filter(|change| change["action"].as_str() == Some("remove"))
+            .filter_map(|change| change["id"].as_str())
+            .map(|id| id.to_string())
+            .collect()
+    }
+
+    /// Returns the (ID, attribute key) pairs whose values are replaced differently by the two sides:
+    /// both sides remove the same value, but add different values (or only one side adds any).
+    fn merge_attribute_conflicts(ours: &[Value], theirs: &[Value]) -> HashSet<(String, String)> {
+        type AttributeChanges = HashMap<(String, String), (HashSet<String>, BTreeSet<String>)>;
+        let attribute_changes = |changes: &[Value]| -> AttributeChanges {
+            let mut ret: AttributeChanges = HashMap::new();
+            for change in changes {
+                if change["what"].as_str() != Some("attribute") {
+                    continue;
+                }
+                let id = change["id"].as_str().unwrap_or("").to_string();
+                let key = change["key"].as_str().unwrap_or("").to_string();
+                let value = change["value"].as_str().unwrap_or("").to_string();
+                let (removed, added) = ret.entry((id, key)).or_default();
+                match change["action"].as_str() {
+                    Some("remove") => removed.insert(value),
+                    _ => added.insert(value),
+                };
+            }
+            ret
+        };
+        let ours = attribute_changes(ours);
+        let theirs = attribute_changes(theirs);
+        ours.into_iter()
+            .filter(
+                |(slot, (removed_ours, added_ours))| match theirs.get(slot) {
+                    Some((removed_theirs, added_theirs)) => {
+                        !removed_ours.is_disjoint(removed_theirs) && added_ours != added_theirs
+                    }
+                    None => false,
+                },
+            )
+            .map(|(slot, _)| slot)
+            .collect()
+    }
+
+    /// Merges two diffs that were made against the same base.
+    /// Changes that do not conflict are combined (identical changes only once) in `changes`.
+    /// Conflicting changes (same feature/column changed differently, the same attribute value replaced differently,
+    /// or a feature changed on one side and removed on the other) are left out of `changes`, and are reported in `conflicts` instead.
+    pub fn merge_diffs(ours: &Value, theirs: &Value) -> Result<Value, Box<dyn Error>> {
+        let (ours, theirs) = match (ours["changes"].as_array(), theirs["changes"].as_array()) {
+            (Some(ours), Some(theirs)) => (ours, theirs),
+            _ => return Err(From::from("merge_diffs: No changes in diff")),
+        };
+        let removed_ours = Self::merge_removed_ids(ours);
+        let removed_theirs = Self::merge_removed_ids(theirs);
+
+        // Rows changed on one side, but removed on the other
+        let is_row_remove =
+            |change: &Value| change["what"] == "row" && change["action"] == "remove";
+        let removal_conflicts: HashSet<&str> = ours
+            .iter()
+            .filter(|change| !is_row_remove(change))
+            .filter_map(|change| change["id"].as_str())
+            .filter(|id| removed_theirs.contains(*id))
+            .chain(
+                theirs
+                    .iter()
+                    .filter(|change| !is_row_remove(change))
+                    .filter_map(|change| change["id"].as_str())
+                    .filter(|id| removed_ours.contains(*id)),
+            )
+            .collect();
+        let attribute_conflicts = Self::merge_attribute_conflicts(ours, theirs);
+
+        let mut conflicts = MergeConflicts::default();
+        let mut slots: BTreeMap<MergeSlot, (Vec<&Value>, Vec<&Value>)> = BTreeMap::new();
+        let sides = [(ours, MergeSide::Ours), (theirs, MergeSide::Theirs)];
+        for (side_changes, side) in &sides {
+            for change in side_changes.iter() {
+                let id = change["id"].as_str().unwrap_or("");
+                if removal_conflicts.contains(id) {
+                    let conflict_key = MergeSlot::Row {
+                        id: id.to_string(),
+                        key: None,
+                    };
+                    conflicts.add(conflict_key, change, *side);
+                    continue;
+                }
+                if change["what"].as_str() == Some("attribute") {
+                    let key = change["key"].as_str().unwrap_or("");
+                    if attribute_conflicts.contains(&(id.to_string(), key.to_string())) {
+                        let conflict_key = MergeSlot::Attribute {
+                            id: id.to_string(),
+                            key: key.to_string(),
+                            value: None,
+                        };
+                        conflicts.add(conflict_key, change, *side);
+                        continue;
+                    }
+                }
+                let slot = slots.entry(Self::merge_slot(change)).or_default();
+                match side {
+                    MergeSide::Ours => slot.0.push(change),
+                    MergeSide::Theirs => slot.1.push(change),
+                }
+            }
+        }
+
+        let mut changes: Vec<Value> = vec![];
+        for (slot, (changes_ours, changes_theirs)) in slots {
+            let mut unique: Vec<&Value> = vec![];
+            changes_ours
+                .iter()
+                .chain(changes_theirs.iter())
+                .for_each(|change| {
+                    if !unique.contains(change) {
+                        unique.push(change)
+                    }
+                });
+            if unique.len() > 1 {
+                // Same slot, different changes
+                changes_ours
+                    .iter()
+                    .for_each(|change| conflicts.add(slot.to_owned(), change, MergeSide::Ours));
+                changes_theirs
+                    .iter()
+                    .for_each(|change| conflicts.add(slot.to_owned(), change, MergeSide::Theirs));
+                continue;
+            }
+            changes.extend(unique.drain(..).map(|change| change.to_owned()));
+        }
+
+        let conflicts = conflicts.into_values();
+        Ok(
+            json!({"format_version": DIFF_FORMAT_VERSION, "changes": changes, "conflicts": conflicts}),
+        )
+    }
+
+    /// Performs a three-way merge of GFF files.
+    /// `ours` and `theirs` are both diffed against `base`, and the merged, non-conflicting changes
+    /// are applied to `base`, which is returned in the gff 1 slot.
+    /// The second return value is the merge result, as returned by `merge_diffs`.
+    pub fn merge_files<S: Into<String>>(
+        base: S,
+        ours: S,
+        theirs: S,
+    ) -> Result<(Self, Value), Box<dyn Error>> {
+        let mut cg = Self::new();
+        cg.record_issues(true);
+        cg.load(GffSource::file(base), DataSet::Base)?;
+        cg.load(GffSource::file(ours), DataSet::Target)?;
+        let diff_ours = cg.diff()?;
+        cg.load(GffSource::file(theirs), DataSet::Target)?;
+        let diff_theirs = cg.diff()?;
+        let mut merged = Self::merge_diffs(&diff_ours, &diff_theirs)?;
+        cg.apply_diff(&merged)?;
+        cg.data2 = None;
+        cg.hierarchy2 = None;
+        if !cg.issues.is_empty() {
+            merged["issues"] = json!(cg.issues);
+        }
+        Ok((cg, merged))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn merge_three_way() {
+        let dir = "test/merge_three_way";
+        let (cg, mut merged) = CompareGFF::merge_files(
+            format!("{}/base.gff", dir),
+            format!("{}/ours.gff", dir),
+            format!("{}/theirs.gff", dir),
+        )
+        .unwrap();
+        let expected = fs::read_to_string(format!("{}/expected.json", dir)).unwrap();
+        let mut expected: Value = serde_json::from_str(&expected).unwrap();
+        CompareGFF::sort_comparison(&mut merged);
+        CompareGFF::sort_comparison(&mut expected);
+        assert_eq!(merged["changes"], expected["changes"]);
+        assert_eq!(merged["conflicts"], expected["conflicts"]);
+
+        // Merged changes are applied to the base
+        let data1 = cg.data1.as_ref().unwrap();
+        assert_eq!(*data1["E0001"].start(), 10);
+        assert_eq!(*data1["E0005"].end(), 1200);
+        assert!(!data1.contains_key("E0003"));
+        assert!(data1.contains_key("C0004"));
+        assert_eq!(*data1["G0001"].end(), 900);
+    }
+
+    #[test]
+    fn merge_identical_changes() {
+        let diff = json!({"changes":[{"action":"update","what":"row","id":"E1","key":"end","value":"1200","old_value":"900"}]});
+        let merged = CompareGFF::merge_diffs(&diff, &diff).unwrap();
+        assert_eq!(merged["changes"], diff["changes"]);
+        assert_eq!(merged["conflicts"], json!([]));
+    }
+
+    #[test]
+    fn merge_attribute_replaced_differently() {
+        let change = |action: &str, value: &str| json!({"action":action,"what":"attribute","id":"G1","key":"Name","value":value});
+        let ours = json!({"changes":[change("remove", "A"), change("add", "B")]});
+        let theirs = json!({"changes":[change("remove", "A"), change("add", "C")]});
+        let merged = CompareGFF::merge_diffs(&ours, &theirs).unwrap();
+        assert_eq!(merged["changes"], json!([]));
+        assert_eq!(
+            merged["conflicts"],
+            json!([{"id":"G1","what":"attribute","key":"Name","ours":ours["changes"],"theirs":theirs["changes"]}])
+        );
+
+        // Same replacement on both sides
+        let merged = CompareGFF::merge_diffs(&ours, &ours).unwrap();
+        assert_eq!(merged["changes"], ours["changes"]);
+        assert_eq!(merged["conflicts"], json!([]));
+    }
+
+    #[test]
+    fn merge_conflict_id_with_tabs() {
+        let update = json!({"action":"update","what":"row","id":"G\t1\t2","key":"end","value":"1200","old_value":"900"});
+        let remove = json!({"action":"remove","what":"row","id":"G\t1\t2"});
+        let merged = CompareGFF::merge_diffs(
+            &json!({ "changes": [update] }),
+            &json!({ "changes": [remove] }),
+        )
+        .unwrap();
+        assert_eq!(
+            merged["conflicts"],
+            json!([{"id":"G\t1\t2","what":"row","ours":[update],"theirs":[remove]}])
+        );
+    }
+}
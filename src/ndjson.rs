@@ -0,0 +1,137 @@
+use crate::{CompareGFF, CompareMode, Hierarchy};
+use ignore_result::Ignore;
+use serde_json::value::Value;
+use std::error::Error;
+use std::io::{BufRead, Write};
+use std::sync::mpsc;
+
+/// Number of elements whose changes can be waiting to be written by `diff_ndjson`.
+const NDJSON_QUEUE_SIZE: usize = 1024;
+
+impl CompareGFF {
+    /// Generates the diff between the two loaded files, and writes each change as a line of JSON (NDJSON)
+    /// as soon as it is found, without building the complete diff in memory. Returns the number of changes.
+    /// The order of the changes is not defined. Issues are not written.
+    pub fn diff_ndjson<W: Write>(&self, mut writer: W) -> Result<usize, Box<dyn Error>> {
+        let (sender, receiver) = mpsc::sync_channel::<Vec<Value>>(NDJSON_QUEUE_SIZE);
+        let mut count = 0;
+        std::thread::scope(|scope| -> Result<(), Box<dyn Error>> {
+            let comparison = scope.spawn(move || -> Result<(), String> {
+                let emit = |changes: Vec<Value>| sender.send(changes).ignore();
+                for mode in [CompareMode::Forward, CompareMode::Reverse] {
+                    self.compare(mode, emit).map_err(|e| e.to_string())?;
+                }
+                Ok(())
+            });
+            // If writing fails, the receiver is dropped, and remaining changes are discarded
+            for changes in receiver {
+                for change in changes {
+                    serde_json::to_writer(&mut writer, &change)?;
+                    writer.write_all(b"\n")?;
+                    count += 1;
+                }
+            }
+            match comparison.join() {
+                Ok(result) => result.map_err(From::from),
+                Err(_) => Err(From::from("diff_ndjson: comparison failed")),
+            }
+        })?;
+        writer.flush()?;
+        Ok(count)
+    }
+
+    /// Applies a diff in NDJSON format (one change per line, as written by `diff_ndjson`) to the data loaded
+    /// into the gff 1 slot, one change at a time, so the diff is never held in memory.
+    /// Unlike `apply_diff`, changes are applied in the order they are read, so attribute changes of an added
+    /// element have to come after its addition. Each change is checked against the diff format schema before
+    /// it is applied. Returns the number of changes applied.
+    pub fn apply_diff_ndjson<R: BufRead>(&mut self, reader: R) -> Result<usize, Box<dyn Error>> {
+        let data = match self.data1.as_mut() {
+            Some(data) => data,
+            _ => return Err(From::from("GFF set 1 needs to be initialized")),
+        };
+        let mut count = 0;
+        for (line_number, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let change: Value = match serde_json::from_str(&line) {
+                Ok(change) => change,
+                Err(e) => {
+                    return Err(From::from(format!(
+                        "apply_diff_ndjson: line {}: {}",
+                        line_number + 1,
+                        e
+                    )))
+                }
+            };
+            if let Err(e) = Self::validate_change(&change) {
+                return Err(From::from(format!(
+                    "apply_diff_ndjson: line {}: {}",
+                    line_number + 1,
+                    e
+                )));
+            }
+            Self::apply_changes(std::slice::from_ref(&change), data)?;
+            count += 1;
+        }
+        self.hierarchy1 = Some(Hierarchy::new(data));
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(dir: &str) -> CompareGFF {
+        let mut cg = CompareGFF::new();
+        cg.load_test_files(dir, "core.gff", "cap.gff");
+        cg
+    }
+
+    #[test]
+    fn diff_ndjson() {
+        let cg = load("gene_split");
+        let mut ndjson: Vec<u8> = vec![];
+        let count = cg.diff_ndjson(&mut ndjson).unwrap();
+        let changes: Vec<Value> = String::from_utf8(ndjson)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(changes.len(), count);
+
+        // Same changes as diff
+        let mut diff = cg.diff().unwrap();
+        let mut streamed = json!({ "changes": changes });
+        CompareGFF::sort_comparison(&mut diff);
+        CompareGFF::sort_comparison(&mut streamed);
+        assert_eq!(diff["changes"], streamed["changes"]);
+    }
+
+    #[test]
+    fn apply_diff_ndjson() {
+        for dir in &["gene_split", "added_exon", "remove_exon", "alter_exon"] {
+            let mut cg = load(dir);
+            let mut ndjson: Vec<u8> = vec![];
+            let count = cg.diff_ndjson(&mut ndjson).unwrap();
+            assert_eq!(cg.apply_diff_ndjson(&ndjson[..]).unwrap(), count);
+
+            // Same result as applying the complete diff
+            let mut batch = load(dir);
+            let diff = batch.diff().unwrap();
+            batch.apply_diff(&diff).unwrap();
+            let as_json = |cg: &CompareGFF| -> Value {
+                cg.data1
+                    .as_ref()
+                    .unwrap()
+                    .iter()
+                    .map(|(id, e)| (id.to_owned(), CompareGFF::gff_to_json(e)))
+                    .collect()
+            };
+            assert_eq!(as_json(&cg), as_json(&batch));
+        }
+    }
+}
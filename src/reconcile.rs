@@ -0,0 +1,165 @@
+use crate::{CompareGFF, HashGFF, Hierarchy};
+use serde_json::value::Value;
+use std::collections::HashMap;
+use std::error::Error;
+
+const RECONCILIATION_COLUMNS: [&str; 11] = [
+    "apollo_id",
+    "id",
+    "method",
+    "confidence",
+    "min_confidence",
+    "elements",
+    "new_elements",
+    "seqname",
+    "start",
+    "end",
+    "name",
+];
+
+/// How an Apollo element was mapped to an original element.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ApolloMatch {
+    /// Via its `orig_id` attribute.
+    OrigId,
+    /// As the only element of its type in the original gene of its siblings.
+    Sibling,
+    /// Via location; reciprocal overlap, and if the original element has the same type.
+    Location(f64, bool),
+}
+
+impl ApolloMatch {
+    pub(crate) fn method(&self) -> &str {
+        match self {
+            ApolloMatch::OrigId => "orig_id",
+            ApolloMatch::Sibling => "sibling",
+            ApolloMatch::Location(_, _) => "location",
+        }
+    }
+
+    /// Returns the confidence of the match, from 0.0 to 1.0.
+    /// Location matches with a different type count half.
+    pub(crate) fn confidence(&self) -> f64 {
+        let confidence = match self {
+            ApolloMatch::OrigId => 1.0,
+            ApolloMatch::Sibling => 0.9,
+            ApolloMatch::Location(overlap, true) => *overlap,
+            ApolloMatch::Location(overlap, false) => *overlap * 0.5,
+        };
+        (confidence * 1000.0).round() / 1000.0
+    }
+}
+
+impl CompareGFF {
+    /// Generates the reconciliation table of an Apollo diff: for each Apollo gene (root element),
+    /// the ID it was mapped to (or the new ID), how it was mapped, and with what confidence.
+    /// `min_confidence` is the lowest confidence of any mapped element of the gene.
+    pub(crate) fn apollo_reconciliation(
+        data2: &HashGFF,
+        hierarchy2: &Hierarchy,
+        original_ids: &HashMap<String, String>,
+        matches: &HashMap<&String, ApolloMatch>,
+    ) -> Value {
+        let mut root_ids: Vec<&String> = data2
+            .keys()
+            .filter(|id| hierarchy2.root_id(id) == Some(*id))
+            .collect();
+        root_ids.sort_by_cached_key(|id| {
+            let e = &data2[*id];
+            (e.seqname().to_string(), *e.start(), id.to_string())
+        });
+        let rows: Vec<Value> = root_ids
+            .iter()
+            .map(|root_id| {
+                let element = &data2[*root_id];
+                let members = hierarchy2.members(root_id);
+                let min_confidence = members
+                    .iter()
+                    .filter_map(|id| matches.get(id))
+                    .map(|apollo_match| apollo_match.confidence())
+                    .fold(None, |min: Option<f64>, c| {
+                        Some(min.map_or(c, |min| min.min(c)))
+                    });
+                let (method, confidence) = match matches.get(root_id) {
+                    Some(apollo_match) => (apollo_match.method(), apollo_match.confidence()),
+                    None => ("new", 0.0),
+                };
+                json!({
+                    "apollo_id": root_id,
+                    "id": original_ids.get(*root_id),
+                    "method": method,
+                    "confidence": confidence,
+                    "min_confidence": min_confidence,
+                    "elements": members.len(),
+                    "new_elements": members.iter().filter(|id| !matches.contains_key(id)).count(),
+                    "seqname": element.seqname(),
+                    "start": element.start(),
+                    "end": element.end(),
+                    "name": element.attributes().get("Name"),
+                })
+            })
+            .collect();
+        json!(rows)
+    }
+
+    /// Renders the reconciliation table of an Apollo diff as tab-separated values, with a header row.
+    pub fn apollo_reconciliation_tsv(diff: &Value) -> Result<String, Box<dyn Error>> {
+        let rows = match diff["reconciliation"].as_array() {
+            Some(rows) => rows,
+            None => return Err(From::from("No reconciliation in diff")),
+        };
+        let mut ret = RECONCILIATION_COLUMNS.join("\t") + "\n";
+        for row in rows {
+            let values: Vec<String> = RECONCILIATION_COLUMNS
+                .iter()
+                .map(|column| match &row[*column] {
+                    Value::Null => String::new(),
+                    Value::String(s) => s.to_owned(),
+                    v => v.to_string(),
+                })
+                .collect();
+            ret += &(values.join("\t") + "\n");
+        }
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apollo_diff(dir: &str) -> Value {
+        let mut cg = CompareGFF::new();
+        cg.load_test_files(dir, "core.gff", "apollo.gff");
+        cg.diff_apollo().unwrap()
+    }
+
+    #[test]
+    fn apollo_reconciliation() {
+        let diff = apollo_diff("apollo_exon_move");
+        let rows = diff["reconciliation"].as_array().unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0]["apollo_id"], json!("a1b2c3d4-0001"));
+        assert_eq!(rows[0]["id"], json!("G0001"));
+        assert_eq!(rows[0]["method"], json!("sibling"));
+        assert_eq!(rows[0]["confidence"], json!(0.9));
+        assert_eq!(rows[0]["elements"], json!(8));
+
+        let tsv = CompareGFF::apollo_reconciliation_tsv(&diff).unwrap();
+        let lines: Vec<&str> = tsv.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("apollo_id\tid\tmethod\tconfidence"));
+        assert!(lines[1].starts_with("a1b2c3d4-0001\tG0001\tsibling\t0.9\t"));
+    }
+
+    #[test]
+    fn apollo_reconciliation_new_gene() {
+        let diff = apollo_diff("apollo_new_deleted");
+        let rows = diff["reconciliation"].as_array().unwrap();
+        let new_genes: Vec<&Value> = rows.iter().filter(|row| row["method"] == "new").collect();
+        assert!(!new_genes.is_empty());
+        assert!(new_genes
+            .iter()
+            .all(|row| row["id"].as_str().unwrap().starts_with("APOLLO_")));
+    }
+}
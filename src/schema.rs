@@ -0,0 +1,228 @@
+use crate::CompareGFF;
+use serde_json::value::Value;
+use std::error::Error;
+use std::sync::OnceLock;
+
+/// Version of the diff format, as written to the `format_version` of every diff.
+pub const DIFF_FORMAT_VERSION: &str = "1.0";
+
+/// The JSON Schema (draft-07) of the diff format.
+pub const DIFF_SCHEMA: &str = include_str!("../schema/diff.schema.json");
+
+/// Maximum number of schema violations reported by `validate_diff`.
+const MAX_SCHEMA_ERRORS: usize = 20;
+
+/// Returns `DIFF_SCHEMA`, parsed once on first use.
+fn diff_schema() -> &'static Value {
+    static SCHEMA: OnceLock<Value> = OnceLock::new();
+    SCHEMA.get_or_init(|| {
+        serde_json::from_str(DIFF_SCHEMA).expect("diff_schema: DIFF_SCHEMA is not valid JSON")
+    })
+}
+
+/// Checks JSON values against a schema. Supports the JSON Schema keywords used by `DIFF_SCHEMA`:
+/// `$ref` (to local definitions), `type`, `const`, `enum`, `minimum`, `required`, `properties`,
+/// `additionalProperties`, `items`, `allOf`, and `if`/`then`/`else`.
+struct SchemaValidator<'a> {
+    root: &'a Value,
+    errors: Vec<String>,
+}
+
+impl<'a> SchemaValidator<'a> {
+    fn new(root: &'a Value) -> Self {
+        Self {
+            root,
+            errors: vec![],
+        }
+    }
+
+    fn error(&mut self, path: &str, message: String) {
+        let path = if path.is_empty() { "/" } else { path };
+        self.errors.push(format!("{}: {}", path, message));
+    }
+
+    fn type_matches(value: &Value, type_name: &str) -> bool {
+        match type_name {
+            "object" => value.is_object(),
+            "array" => value.is_array(),
+            "string" => value.is_string(),
+            "number" => value.is_number(),
+            "integer" => value.is_i64() || value.is_u64(),
+            "boolean" => value.is_boolean(),
+            "null" => value.is_null(),
+            _ => false,
+        }
+    }
+
+    /// Checks if a value matches a schema, without recording errors.
+    fn matches(&self, value: &Value, schema: &Value) -> bool {
+        let mut validator = SchemaValidator::new(self.root);
+        validator.validate(value, schema, "");
+        validator.errors.is_empty()
+    }
+
+    fn validate(&mut self, value: &Value, schema: &Value, path: &str) {
+        if let Some(reference) = schema["$ref"].as_str() {
+            match self.root.pointer(reference.trim_start_matches('#')) {
+                Some(schema) => self.validate(value, schema, path),
+                None => self.error(path, format!("unknown schema reference {}", reference)),
+            }
+            return;
+        }
+
+        let types: Vec<&str> = match &schema["type"] {
+            Value::String(type_name) => vec![type_name],
+            Value::Array(type_names) => type_names.iter().filter_map(|t| t.as_str()).collect(),
+            _ => vec![],
+        };
+        if !types.is_empty() && !types.iter().any(|t| Self::type_matches(value, t)) {
+            self.error(
+                path,
+                format!("expected {}, found {}", types.join(" or "), value),
+            );
+            return;
+        }
+        if let Some(expected) = schema.get("const") {
+            if value != expected {
+                self.error(path, format!("expected {}, found {}", expected, value));
+            }
+        }
+        if let Some(options) = schema["enum"].as_array() {
+            if !options.contains(value) {
+                let options: Vec<String> = options.iter().map(|o| o.to_string()).collect();
+                self.error(
+                    path,
+                    format!("{} is not one of {}", value, options.join(", ")),
+                );
+            }
+        }
+        if let (Some(minimum), Some(number)) = (schema["minimum"].as_f64(), value.as_f64()) {
+            if number < minimum {
+                self.error(path, format!("{} is less than {}", number, minimum));
+            }
+        }
+
+        if let Some(object) = value.as_object() {
+            for key in schema["required"].as_array().into_iter().flatten() {
+                if let Some(key) = key.as_str() {
+                    if !object.contains_key(key) {
+                        self.error(path, format!("missing required key '{}'", key));
+                    }
+                }
+            }
+            let properties = schema["properties"].as_object();
+            for (key, sub_value) in object {
+                let sub_path = format!("{}/{}", path, key);
+                match properties.and_then(|properties| properties.get(key)) {
+                    Some(sub_schema) => self.validate(sub_value, sub_schema, &sub_path),
+                    None => match &schema["additionalProperties"] {
+                        Value::Bool(false) => self.error(path, format!("unexpected key '{}'", key)),
+                        Value::Object(_) => {
+                            let sub_schema = &schema["additionalProperties"];
+                            self.validate(sub_value, sub_schema, &sub_path)
+                        }
+                        _ => {}
+                    },
+                }
+            }
+        }
+        if let (Some(array), Some(items)) = (value.as_array(), schema.get("items")) {
+            for (num, item) in array.iter().enumerate() {
+                self.validate(item, items, &format!("{}/{}", path, num));
+            }
+        }
+
+        for sub_schema in schema["allOf"].as_array().into_iter().flatten() {
+            self.validate(value, sub_schema, path);
+        }
+        if let Some(condition) = schema.get("if") {
+            let branch = match self.matches(value, condition) {
+                true => schema.get("then"),
+                false => schema.get("else"),
+            };
+            if let Some(branch) = branch {
+                self.validate(value, branch, path);
+            }
+        }
+    }
+}
+
+impl CompareGFF {
+    /// Checks a value against a part of the diff schema, given as a JSON pointer (empty for the whole schema).
+    fn validate_schema(value: &Value, pointer: &str) -> Result<(), Box<dyn Error>> {
+        let schema = diff_schema();
+        let sub_schema = match schema.pointer(pointer) {
+            Some(sub_schema) => sub_schema,
+            None => return Err(From::from(format!("No schema for {}", pointer))),
+        };
+        let mut validator = SchemaValidator::new(schema);
+        validator.validate(value, sub_schema, "");
+        if validator.errors.is_empty() {
+            return Ok(());
+        }
+        let total = validator.errors.len();
+        let mut errors: Vec<String> = validator
+            .errors
+            .into_iter()
+            .take(MAX_SCHEMA_ERRORS)
+            .collect();
+        if total > MAX_SCHEMA_ERRORS {
+            errors.push(format!("... and {} more", total - MAX_SCHEMA_ERRORS));
+        }
+        Err(From::from(format!("Invalid diff:\n{}", errors.join("\n"))))
+    }
+
+    /// Checks a diff against the diff format schema (`DIFF_SCHEMA`).
+    /// Returns an error listing the violations, with the JSON pointer of each.
+    pub fn validate_diff(diff: &Value) -> Result<(), Box<dyn Error>> {
+        Self::validate_schema(diff, "")
+    }
+
+    /// Checks a single change (e.g. a line of an NDJSON diff) against the diff format schema.
+    pub fn validate_change(change: &Value) -> Result<(), Box<dyn Error>> {
+        Self::validate_schema(change, "/definitions/change")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DataSet, GffSource};
+
+    #[test]
+    fn validate_generated_diffs() {
+        for dir in &["gene_split", "added_exon", "remove_exon", "alter_exon"] {
+            let mut cg = CompareGFF::new();
+            cg.load_test_files(dir, "core.gff", "cap.gff");
+            let diff = cg.diff().unwrap();
+            assert_eq!(diff["format_version"], json!(DIFF_FORMAT_VERSION));
+            CompareGFF::validate_diff(&diff).unwrap();
+            CompareGFF::validate_diff(&CompareGFF::invert_diff(&diff).unwrap()).unwrap();
+        }
+        let mut cg = CompareGFF::new();
+        cg.load_test_files("apollo_new_deleted", "core.gff", "apollo.gff");
+        CompareGFF::validate_diff(&cg.diff_apollo().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn validate_invalid_diff() {
+        let diff = json!({"format_version":"2.0","changes":[
+            {"action":"update","what":"row","id":"E1","key":"length","value":"1200"},
+            {"action":"add","what":"row","id":"E2","added_element":{"seqname":"chr1"}},
+            {"action":"update","what":"attribute","key":"Note","value":"x"}
+        ]});
+        let error = CompareGFF::validate_diff(&diff).unwrap_err().to_string();
+        assert!(error.contains("/format_version: \"2.0\" is not one of \"1.0\""));
+        assert!(error.contains("/changes/0/key: \"length\" is not one of"));
+        assert!(error.contains("/changes/1/added_element: missing required key 'start'"));
+        assert!(error.contains("/changes/2: missing required key 'id'"));
+        assert!(error.contains("/changes/2/action: \"update\" is not one of \"add\", \"remove\""));
+        assert!(CompareGFF::validate_diff(&json!({"changes":{}})).is_err());
+        assert!(CompareGFF::validate_diff(&json!({"changes":[]})).is_ok());
+
+        let mut cg = CompareGFF::new();
+        cg.load(GffSource::file("test/alter_exon/core.gff"), DataSet::Base)
+            .unwrap();
+        assert!(cg.apply_diff(&diff).is_err());
+    }
+}
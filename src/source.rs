@@ -0,0 +1,221 @@
+use crate::{CompareGFF, GffFormat, Region};
+use std::error::Error;
+
+/// The data sets of a CompareGFF that can be diffed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DataSet {
+    /// The original data (gff 1 slot); diffs are applied to this one.
+    Base,
+    /// The modified data (gff 2 slot).
+    Target,
+}
+
+/// Where GFF data is loaded from.
+pub enum GffSource {
+    /// A file name; compressed files (gzip/BGZF, bzip2, xz, zstd) are decompressed. `-` reads from STDIN.
+    File(String),
+    /// Any reader, e.g. a `BufRead` or a network stream; compressed data is decompressed.
+    Reader(Box<dyn std::io::Read>),
+    /// GFF text held in memory.
+    Text(String),
+    /// Already parsed records.
+    Records(Vec<bio::io::gff::Record>),
+    /// A file name of an Apollo JSON feature export; compressed files are decompressed.
+    ApolloJson(String),
+}
+
+impl GffSource {
+    pub fn file<S: Into<String>>(filename: S) -> Self {
+        GffSource::File(filename.into())
+    }
+
+    pub fn reader<R: std::io::Read + 'static>(reader: R) -> Self {
+        GffSource::Reader(Box::new(reader))
+    }
+
+    pub fn text<S: Into<String>>(text: S) -> Self {
+        GffSource::Text(text.into())
+    }
+
+    pub fn records(records: Vec<bio::io::gff::Record>) -> Self {
+        GffSource::Records(records)
+    }
+
+    pub fn apollo_json<S: Into<String>>(filename: S) -> Self {
+        GffSource::ApolloJson(filename.into())
+    }
+}
+
+/// Builds a CompareGFF with its data sets and options.
+pub struct CompareGFFBuilder {
+    base: Option<GffSource>,
+    target: Option<GffSource>,
+    versions: Vec<(String, GffSource)>,
+    record_issues: bool,
+    flexible_ids: bool,
+    input_format: Option<GffFormat>,
+    region: Option<Region>,
+    apollo_id_prefix: Option<String>,
+    apollo_ignored_attributes: Option<Vec<String>>,
+    apollo_compared_attributes: Option<Vec<String>>,
+}
+
+impl CompareGFFBuilder {
+    pub fn new() -> Self {
+        Self {
+            base: None,
+            target: None,
+            versions: vec![],
+            record_issues: false,
+            flexible_ids: true,
+            input_format: None,
+            region: None,
+            apollo_id_prefix: None,
+            apollo_ignored_attributes: None,
+            apollo_compared_attributes: None,
+        }
+    }
+
+    /// Sets the original data.
+    pub fn base(mut self, source: GffSource) -> Self {
+        self.base = Some(source);
+        self
+    }
+
+    /// Sets the modified data.
+    pub fn target(mut self, source: GffSource) -> Self {
+        self.target = Some(source);
+        self
+    }
+
+    /// Adds an annotation version, for `history`.
+    pub fn version<S: Into<String>>(mut self, label: S, source: GffSource) -> Self {
+        self.versions.push((label.into(), source));
+        self
+    }
+
+    /// Changes the option to record issues in the result.
+    pub fn record_issues(mut self, do_record: bool) -> Self {
+        self.record_issues = do_record;
+        self
+    }
+
+    /// Changes the option to create new IDs in case of duplicates.
+    pub fn flexible_ids(mut self, use_flexible_ids: bool) -> Self {
+        self.flexible_ids = use_flexible_ids;
+        self
+    }
+
+    /// Sets the format of loaded data, instead of detecting it from the content.
+    pub fn input_format(mut self, format: GffFormat) -> Self {
+        self.input_format = Some(format);
+        self
+    }
+
+    /// Restricts loaded data to elements overlapping a region, using tabix/CSI indices where available.
+    pub fn region(mut self, region: Region) -> Self {
+        self.region = Some(region);
+        self
+    }
+
+    /// Changes the prefix for IDs of new elements in Apollo diffs.
+    pub fn apollo_id_prefix<S: Into<String>>(mut self, prefix: S) -> Self {
+        self.apollo_id_prefix = Some(prefix.into());
+        self
+    }
+
+    /// Changes the attribute keys ignored in Apollo diffs.
+    pub fn apollo_ignored_attributes(mut self, keys: Vec<String>) -> Self {
+        self.apollo_ignored_attributes = Some(keys);
+        self
+    }
+
+    /// Restricts the attribute keys compared in Apollo diffs.
+    pub fn apollo_compared_attributes(mut self, keys: Vec<String>) -> Self {
+        self.apollo_compared_attributes = Some(keys);
+        self
+    }
+
+    /// Creates the CompareGFF, loading all given data sets.
+    pub fn build(self) -> Result<CompareGFF, Box<dyn Error>> {
+        let mut ret = CompareGFF::new();
+        ret.record_issues(self.record_issues);
+        ret.flexible_ids(self.flexible_ids);
+        ret.input_format(self.input_format);
+        ret.region(self.region);
+        if let Some(prefix) = self.apollo_id_prefix {
+            ret.apollo_id_prefix(prefix);
+        }
+        if let Some(keys) = self.apollo_ignored_attributes {
+            ret.apollo_ignored_attributes(keys);
+        }
+        ret.apollo_compared_attributes(self.apollo_compared_attributes);
+        if let Some(source) = self.base {
+            ret.load(source, DataSet::Base)?;
+        }
+        if let Some(source) = self.target {
+            ret.load(source, DataSet::Target)?;
+        }
+        for (label, source) in self.versions {
+            ret.load_version(label, source)?;
+        }
+        Ok(ret)
+    }
+}
+
+impl Default for CompareGFFBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CompareGFF {
+    /// Returns a builder for a new CompareGFF.
+    pub fn builder() -> CompareGFFBuilder {
+        CompareGFFBuilder::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn builder_from_text_and_reader() {
+        let core = fs::read_to_string("test/alter_exon/core.gff").unwrap();
+        let cap = fs::File::open("test/alter_exon/cap.gff").unwrap();
+        let cg = CompareGFF::builder()
+            .base(GffSource::text(core))
+            .target(GffSource::reader(std::io::BufReader::new(cap)))
+            .record_issues(true)
+            .build()
+            .unwrap();
+        assert_eq!(cg.diff().unwrap()["changes"].as_array().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn load_records() {
+        let mut record = bio::io::gff::Record::new();
+        *record.seqname_mut() = "chr1".to_string();
+        *record.feature_type_mut() = "gene".to_string();
+        *record.start_mut() = 100;
+        *record.end_mut() = 200;
+        record
+            .attributes_mut()
+            .insert("ID".to_string(), "G1".to_string());
+        let mut modified = record.clone();
+        *modified.end_mut() = 300;
+
+        let mut cg = CompareGFF::new();
+        cg.load(GffSource::records(vec![record]), DataSet::Base)
+            .unwrap();
+        cg.load(GffSource::records(vec![modified]), DataSet::Target)
+            .unwrap();
+        let diff = cg.diff().unwrap();
+        assert_eq!(
+            diff["changes"],
+            json!([{"action":"update","what":"row","id":"G1","key":"end","value":"300","old_value":"200"}])
+        );
+    }
+}
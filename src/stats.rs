@@ -0,0 +1,129 @@
+use crate::diff_features::ChangedFeature;
+use crate::{CompareGFF, DataSet};
+use serde_json::value::Value;
+use std::collections::{BTreeMap, HashSet};
+use std::error::Error;
+
+/// Counts of added, removed, and updated rows.
+#[derive(Default)]
+struct RowCounts {
+    added: usize,
+    removed: usize,
+    updated: usize,
+}
+
+impl RowCounts {
+    fn count(&mut self, status: &str) {
+        match status {
+            "added" => self.added += 1,
+            "removed" => self.removed += 1,
+            _ => self.updated += 1,
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        json!({"added": self.added, "removed": self.removed, "updated": self.updated})
+    }
+}
+
+impl CompareGFF {
+    /// Generates summary statistics of a diff: the number of changes, added/removed/updated rows per feature
+    /// type and per seqname, attribute values added/removed per attribute key, the number of genes affected,
+    /// and the number of issues.
+    /// Rows with only attribute changes are not counted as updated.
+    /// `base` is the data set the diff applies to (the target for reversed diffs).
+    pub fn diff_stats(&self, diff: &Value, base: DataSet) -> Result<Value, Box<dyn Error>> {
+        let data = self.loaded_data(base)?;
+        let changed_features = self.diff_features(diff, base)?;
+        let features = ChangedFeature::by_id(&changed_features);
+
+        let mut by_feature_type: BTreeMap<String, RowCounts> = BTreeMap::new();
+        let mut by_seqname: BTreeMap<String, RowCounts> = BTreeMap::new();
+        let mut gene_ids: HashSet<String> = HashSet::new();
+        for feature in &changed_features {
+            gene_ids.insert(Self::table_root_id(&feature.id, &features, data));
+            let status = match feature.status() {
+                "modified" if feature.changed_columns().is_empty() => continue,
+                status => status,
+            };
+            let e = feature.element();
+            by_feature_type
+                .entry(e.feature_type().to_string())
+                .or_default()
+                .count(status);
+            by_seqname
+                .entry(e.seqname().to_string())
+                .or_default()
+                .count(status);
+        }
+
+        let changes = diff["changes"].as_array();
+        let mut attributes: BTreeMap<&str, (usize, usize)> = BTreeMap::new();
+        for change in changes.into_iter().flatten() {
+            if change["what"].as_str() != Some("attribute") {
+                continue;
+            }
+            let counts = attributes
+                .entry(change["key"].as_str().unwrap_or(""))
+                .or_default();
+            match change["action"].as_str() {
+                Some("add") => counts.0 += 1,
+                Some("remove") => counts.1 += 1,
+                _ => {}
+            }
+        }
+
+        let rows = |counts: BTreeMap<String, RowCounts>| -> Value {
+            counts
+                .into_iter()
+                .map(|(key, counts)| (key, counts.to_json()))
+                .collect()
+        };
+        let attributes: Value = attributes
+            .into_iter()
+            .map(|(key, (added, removed))| {
+                (key.to_string(), json!({"added": added, "removed": removed}))
+            })
+            .collect();
+        Ok(json!({
+            "changes": changes.map_or(0, |changes| changes.len()),
+            "rows": {
+                "by_feature_type": rows(by_feature_type),
+                "by_seqname": rows(by_seqname)
+            },
+            "attributes": attributes,
+            "genes_affected": gene_ids.len(),
+            "issues": diff["issues"].as_array().map_or(0, |issues| issues.len())
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff_stats() {
+        let mut cg = CompareGFF::new();
+        cg.load_test_files("gene_split", "core.gff", "cap.gff");
+        let diff = cg.diff().unwrap();
+        let stats = cg.diff_stats(&diff, DataSet::Base).unwrap();
+        assert_eq!(
+            stats["changes"],
+            json!(diff["changes"].as_array().unwrap().len())
+        );
+        assert_eq!(
+            stats["rows"]["by_feature_type"]["gene"],
+            json!({"added": 1, "removed": 0, "updated": 1})
+        );
+        assert_eq!(
+            stats["rows"]["by_feature_type"]["mRNA"],
+            json!({"added": 1, "removed": 0, "updated": 1})
+        );
+        assert_eq!(stats["rows"]["by_seqname"]["Scaffold0"]["added"], json!(2));
+        assert!(stats["rows"]["by_feature_type"]["exon"].is_null());
+        assert_eq!(stats["attributes"]["Parent"]["removed"], json!(4));
+        assert_eq!(stats["genes_affected"], json!(2));
+        assert_eq!(stats["issues"], json!(0));
+    }
+}
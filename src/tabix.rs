@@ -0,0 +1,548 @@
+use crate::CompareGFF;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, Cursor, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::str::FromStr;
+
+/// A genomic region; 1-based, inclusive, like GFF coordinates.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Region {
+    pub seqname: String,
+    pub start: u64,
+    pub end: u64,
+}
+
+impl Region {
+    pub fn new<S: Into<String>>(seqname: S, start: u64, end: u64) -> Self {
+        Self {
+            seqname: seqname.into(),
+            start,
+            end,
+        }
+    }
+
+    /// Checks if a GFF element overlaps the region.
+    pub fn overlaps(&self, element: &bio::io::gff::Record) -> bool {
+        element.seqname() == self.seqname
+            && *element.start() <= self.end
+            && *element.end() >= self.start
+    }
+}
+
+impl FromStr for Region {
+    type Err = String;
+
+    /// Parses `SEQNAME:START-END`, or `SEQNAME` for a whole sequence.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (seqname, range) = match s.rfind(':') {
+            Some(pos) => (&s[..pos], Some(&s[pos + 1..])),
+            None => (s, None),
+        };
+        let (start, end) = match range {
+            Some(range) => {
+                let parts: Vec<u64> = range
+                    .replace(',', "")
+                    .split('-')
+                    .map(|n| n.parse::<u64>())
+                    .collect::<Result<Vec<u64>, _>>()
+                    .map_err(|_| format!("Bad region '{}', use SEQNAME:START-END", s))?;
+                match parts.as_slice() {
+                    [start, end] if *start >= 1 && start <= end => (*start, *end),
+                    _ => return Err(format!("Bad region '{}', use SEQNAME:START-END", s)),
+                }
+            }
+            None => (1, u64::MAX),
+        };
+        if seqname.is_empty() {
+            return Err(format!("Bad region '{}', no sequence name", s));
+        }
+        Ok(Self::new(seqname, start, end))
+    }
+}
+
+/// Reads BGZF blocks, and lines with their virtual offsets.
+struct BgzfReader<R: Read + Seek> {
+    inner: R,
+    block: Vec<u8>,
+    block_offset: u64,
+    next_block_offset: u64,
+    position: usize,
+    blocks_read: usize,
+}
+
+impl<R: Read + Seek> BgzfReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            block: vec![],
+            block_offset: 0,
+            next_block_offset: 0,
+            position: 0,
+            blocks_read: 0,
+        }
+    }
+
+    /// Reads and decompresses the block at the given file offset. Returns false at the end of the file.
+    fn read_block(&mut self, offset: u64) -> Result<bool, Box<dyn Error>> {
+        self.inner.seek(SeekFrom::Start(offset))?;
+        let mut header = [0u8; 18];
+        match self.inner.read_exact(&mut header) {
+            Ok(_) => {}
+            Err(ref e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(false),
+            Err(e) => return Err(Box::new(e)),
+        }
+        if header[0..4] != [0x1f, 0x8b, 8, 4] || header[12..14] != [b'B', b'C'] {
+            return Err(From::from(format!("Not a BGZF block at offset {}", offset)));
+        }
+        let block_size = u16::from_le_bytes([header[16], header[17]]) as usize + 1;
+        let mut data = header.to_vec();
+        data.resize(block_size, 0);
+        self.inner.read_exact(&mut data[18..])?;
+        self.block.clear();
+        libflate::gzip::Decoder::new(&data[..])?.read_to_end(&mut self.block)?;
+        self.block_offset = offset;
+        self.next_block_offset = offset + block_size as u64;
+        self.position = 0;
+        self.blocks_read += 1;
+        Ok(true)
+    }
+
+    fn seek_virtual(&mut self, virtual_offset: u64) -> Result<(), Box<dyn Error>> {
+        self.read_block(virtual_offset >> 16)?;
+        self.position = (virtual_offset & 0xffff) as usize;
+        Ok(())
+    }
+
+    /// Moves to the start of the next non-empty block, if the current one is used up.
+    /// Returns false at the end of the file.
+    fn fill(&mut self) -> Result<bool, Box<dyn Error>> {
+        while self.position >= self.block.len() {
+            if !self.read_block(self.next_block_offset)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn virtual_offset(&self) -> u64 {
+        (self.block_offset << 16) | self.position as u64
+    }
+
+    /// Reads a line, without the line break. Returns the virtual offset of its start.
+    fn read_line(&mut self, line: &mut Vec<u8>) -> Result<Option<u64>, Box<dyn Error>> {
+        line.clear();
+        if !self.fill()? {
+            return Ok(None);
+        }
+        let start = self.virtual_offset();
+        loop {
+            let rest = &self.block[self.position..];
+            match rest.iter().position(|c| *c == b'\n') {
+                Some(pos) => {
+                    line.extend_from_slice(&rest[..pos]);
+                    self.position += pos + 1;
+                    return Ok(Some(start));
+                }
+                None => {
+                    line.extend_from_slice(rest);
+                    self.position = self.block.len();
+                    if !self.fill()? {
+                        return Ok(Some(start));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Reads and decompresses the remaining data.
+    fn read_to_end(&mut self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut ret: Vec<u8> = vec![];
+        while self.fill()? {
+            ret.extend_from_slice(&self.block[self.position..]);
+            self.position = self.block.len();
+        }
+        Ok(ret)
+    }
+}
+
+/// A chunk of a BGZF file, in virtual offsets.
+type Chunk = (u64, u64);
+
+/// A bin of an index: its number, the virtual offset of the first feature overlapping its start (CSI only),
+/// and its chunks.
+type Bin = (u32, u64, Vec<Chunk>);
+
+/// The index of a reference sequence: bins, and the linear index (tabix only).
+type ReferenceIndex = (Vec<Bin>, Vec<u64>);
+
+/// The sequence, start and end columns, the meta character, and the sequence names of a tabix header.
+type TabixHeader = (usize, usize, usize, u8, Vec<String>);
+
+/// A tabix (`.tbi`) or CSI (`.csi`) index of a BGZF-compressed GFF file.
+struct TabixIndex {
+    min_shift: u32,
+    depth: u32,
+    col_seq: usize,
+    col_beg: usize,
+    col_end: usize,
+    meta: u8,
+    names: Vec<String>,
+    references: Vec<ReferenceIndex>,
+}
+
+struct IndexParser {
+    data: Cursor<Vec<u8>>,
+}
+
+impl IndexParser {
+    fn bytes<const N: usize>(&mut self) -> Result<[u8; N], Box<dyn Error>> {
+        let mut ret = [0u8; N];
+        self.data.read_exact(&mut ret)?;
+        Ok(ret)
+    }
+
+    fn i32(&mut self) -> Result<i32, Box<dyn Error>> {
+        Ok(i32::from_le_bytes(self.bytes()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, Box<dyn Error>> {
+        Ok(u32::from_le_bytes(self.bytes()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, Box<dyn Error>> {
+        Ok(u64::from_le_bytes(self.bytes()?))
+    }
+
+    fn count(&mut self) -> Result<usize, Box<dyn Error>> {
+        let n = self.i32()?;
+        if n < 0 {
+            return Err(From::from(format!("Bad index: negative count {}", n)));
+        }
+        Ok(n as usize)
+    }
+
+    fn chunks(&mut self) -> Result<Vec<Chunk>, Box<dyn Error>> {
+        (0..self.count()?)
+            .map(|_| Ok((self.u64()?, self.u64()?)))
+            .collect()
+    }
+
+    /// Parses the tabix header.
+    fn tabix_header(&mut self) -> Result<TabixHeader, Box<dyn Error>> {
+        let _format = self.i32()?;
+        let col_seq = self.count()?;
+        let col_beg = self.count()?;
+        let col_end = self.count()?;
+        let meta = self.i32()? as u8;
+        let _skip = self.i32()?;
+        let mut names = vec![0u8; self.count()?];
+        self.data.read_exact(&mut names)?;
+        let names: Vec<String> = names
+            .split(|c| *c == 0)
+            .filter(|name| !name.is_empty())
+            .map(|name| String::from_utf8_lossy(name).to_string())
+            .collect();
+        Ok((col_seq, col_beg, col_end, meta, names))
+    }
+}
+
+impl TabixIndex {
+    fn from_file(filename: &Path) -> Result<Self, Box<dyn Error>> {
+        let data = BgzfReader::new(BufReader::new(File::open(filename)?)).read_to_end()?;
+        let mut p = IndexParser {
+            data: Cursor::new(data),
+        };
+        let magic: [u8; 4] = p.bytes()?;
+        let csi = match &magic {
+            b"TBI\x01" => false,
+            b"CSI\x01" => true,
+            _ => return Err(From::from(format!("Not a tabix/CSI index: {:?}", filename))),
+        };
+        let (min_shift, depth, (col_seq, col_beg, col_end, meta, names), n_ref) = match csi {
+            true => {
+                let (min_shift, depth) = (p.u32()?, p.u32()?);
+                // The tabix header is optional in CSI, as auxiliary data
+                let mut aux = vec![0u8; p.count()?];
+                p.data.read_exact(&mut aux)?;
+                if aux.is_empty() {
+                    return Err(From::from(format!(
+                        "CSI index without sequence names: {:?}",
+                        filename
+                    )));
+                }
+                let header = IndexParser {
+                    data: Cursor::new(aux),
+                }
+                .tabix_header()?;
+                (min_shift, depth, header, p.count()?)
+            }
+            false => {
+                let n_ref = p.count()?;
+                (14, 5, p.tabix_header()?, n_ref)
+            }
+        };
+        let mut references = vec![];
+        for _ in 0..n_ref {
+            let mut bins = vec![];
+            for _ in 0..p.count()? {
+                let bin = p.u32()?;
+                let loffset = if csi { p.u64()? } else { 0 };
+                bins.push((bin, loffset, p.chunks()?));
+            }
+            let linear = match csi {
+                true => vec![],
+                false => (0..p.count()?)
+                    .map(|_| p.u64())
+                    .collect::<Result<Vec<u64>, _>>()?,
+            };
+            references.push((bins, linear));
+        }
+        Ok(Self {
+            min_shift,
+            depth,
+            col_seq,
+            col_beg,
+            col_end,
+            meta,
+            names,
+            references,
+        })
+    }
+
+    /// Returns the bins that may hold features overlapping the 0-based, half-open interval.
+    fn region_to_bins(&self, beg: u64, end: u64) -> HashSet<u32> {
+        let max = 1u64 << (self.min_shift + self.depth * 3);
+        let end = end.min(max).saturating_sub(1);
+        let beg = beg.min(end);
+        let mut ret = HashSet::new();
+        let mut shift = self.min_shift + self.depth * 3;
+        let mut offset = 0u64;
+        for level in 0..=self.depth {
+            for bin in (offset + (beg >> shift))..=(offset + (end >> shift)) {
+                ret.insert(bin as u32);
+            }
+            offset += 1 << (level * 3);
+            shift -= 3;
+        }
+        ret
+    }
+
+    /// Returns the smallest virtual offset of features that may overlap a 0-based position.
+    /// Tabix has a linear index for this; CSI has the offset of the deepest existing bin at or left of the
+    /// position, found the way htslib does.
+    fn min_offset(&self, reference: &ReferenceIndex, beg: u64) -> u64 {
+        let (bins, linear) = reference;
+        if !linear.is_empty() {
+            return linear
+                .get((beg >> self.min_shift) as usize)
+                .or_else(|| linear.last())
+                .copied()
+                .unwrap_or(0);
+        }
+        let first_leaf = ((1u64 << (self.depth * 3)) - 1) / 7;
+        let mut bin = first_leaf + (beg >> self.min_shift);
+        loop {
+            if let Some((_bin, loffset, _chunks)) = bins.iter().find(|(b, _, _)| *b as u64 == bin) {
+                return *loffset;
+            }
+            if bin == 0 {
+                return 0;
+            }
+            let first_sibling = (((bin - 1) >> 3) << 3) + 1;
+            bin = match bin > first_sibling {
+                true => bin - 1,
+                false => (bin - 1) >> 3,
+            };
+        }
+    }
+
+    /// Returns the merged chunks that may hold features overlapping the region.
+    fn chunks(&self, region: &Region) -> Vec<Chunk> {
+        let reference = match self.names.iter().position(|name| *name == region.seqname) {
+            Some(reference) => &self.references[reference],
+            None => return vec![],
+        };
+        let beg = region.start.saturating_sub(1);
+        let bins = self.region_to_bins(beg, region.end);
+        let min_offset = self.min_offset(reference, beg);
+        let mut chunks: Vec<Chunk> = reference
+            .0
+            .iter()
+            .filter(|(bin, _loffset, _chunks)| bins.contains(bin))
+            .flat_map(|(_bin, _loffset, chunks)| chunks.iter().copied())
+            .filter(|(_start, end)| *end > min_offset)
+            .collect();
+        chunks.sort();
+        let mut ret: Vec<Chunk> = vec![];
+        for chunk in chunks {
+            match ret.last_mut() {
+                Some(last) if chunk.0 <= last.1 => last.1 = last.1.max(chunk.1),
+                _ => ret.push(chunk),
+            }
+        }
+        ret
+    }
+
+    /// Checks if a line overlaps the region, using the index columns.
+    fn line_overlaps(&self, line: &str, region: &Region) -> bool {
+        let columns: Vec<&str> = line.split('\t').collect();
+        let column = |n: usize| columns.get(n.wrapping_sub(1)).copied();
+        let parse = |n: usize| column(n).and_then(|s| s.parse::<u64>().ok());
+        match (
+            column(self.col_seq),
+            parse(self.col_beg),
+            parse(self.col_end),
+        ) {
+            (Some(seqname), Some(start), Some(end)) => {
+                seqname == region.seqname && start <= region.end && end >= region.start
+            }
+            _ => false,
+        }
+    }
+}
+
+impl CompareGFF {
+    /// Returns the tabix/CSI index file of a BGZF-compressed file, if there is one.
+    pub(crate) fn tabix_index_file(filename: &str) -> Option<String> {
+        [".tbi", ".csi"]
+            .iter()
+            .map(|ext| format!("{}{}", filename, ext))
+            .find(|index_file| Path::new(index_file).is_file())
+    }
+
+    /// Reads the lines of a tabix-indexed, BGZF-compressed file that overlap a region,
+    /// decompressing only the blocks that the index points to.
+    /// Returns the lines, and the number of decompressed blocks.
+    pub(crate) fn read_tabix_region(
+        filename: &str,
+        index_file: &str,
+        region: &Region,
+    ) -> Result<(String, usize), Box<dyn Error>> {
+        let index = TabixIndex::from_file(Path::new(index_file))?;
+        let mut reader = BgzfReader::new(BufReader::new(File::open(filename)?));
+        let mut ret = String::new();
+        let mut line: Vec<u8> = vec![];
+        for (start, end) in index.chunks(region) {
+            reader.seek_virtual(start)?;
+            while let Some(offset) = reader.read_line(&mut line)? {
+                if offset >= end {
+                    break;
+                }
+                let line = String::from_utf8_lossy(&line);
+                if line.as_bytes().first() == Some(&index.meta) {
+                    continue;
+                }
+                if index.line_overlaps(&line, region) {
+                    ret += &line;
+                    ret.push('\n');
+                }
+            }
+        }
+        Ok((ret, reader.blocks_read))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_region() {
+        assert_eq!(
+            "chr1:1,000-2000".parse::<Region>(),
+            Ok(Region::new("chr1", 1000, 2000))
+        );
+        assert_eq!(
+            "chr1".parse::<Region>(),
+            Ok(Region::new("chr1", 1, u64::MAX))
+        );
+        assert!("chr1:2000-1000".parse::<Region>().is_err());
+        assert!("chr1:0-0".parse::<Region>().is_err());
+        assert!("chr1:0-100".parse::<Region>().is_err());
+        assert!("chr1:abc".parse::<Region>().is_err());
+    }
+
+    #[test]
+    fn read_tabix_region() {
+        let region = Region::new("chr1", 150000, 250000);
+        for (file, index_file) in &[
+            (
+                "test/tabix_region/base.gff.gz",
+                "test/tabix_region/base.gff.gz.tbi",
+            ),
+            (
+                "test/tabix_region/modified.gff.gz",
+                "test/tabix_region/modified.gff.gz.csi",
+            ),
+        ] {
+            assert_eq!(
+                CompareGFF::tabix_index_file(file),
+                Some(index_file.to_string())
+            );
+            let (lines, blocks) = CompareGFF::read_tabix_region(file, index_file, &region).unwrap();
+            // Genes 8 to 12, with mRNA and two exons each
+            assert_eq!(lines.lines().count(), 20);
+            assert!(lines.lines().all(|line| line.starts_with("chr1\t")));
+            assert!(blocks < 5);
+        }
+    }
+
+    #[test]
+    fn csi_min_offset() {
+        // Position 200000 is in leaf bin 4681 + (200000 >> 14) = 4693
+        let index = TabixIndex {
+            min_shift: 14,
+            depth: 5,
+            col_seq: 1,
+            col_beg: 4,
+            col_end: 5,
+            meta: b'#',
+            names: vec!["chr1".to_string()],
+            references: vec![(
+                vec![
+                    (0, 0, vec![(0, 100), (550, 700)]),
+                    (4693, 500, vec![(500, 600)]),
+                ],
+                vec![],
+            )],
+        };
+        let region = Region::new("chr1", 200001, 200100);
+        assert_eq!(index.chunks(&region), vec![(500, 700)]);
+        // No bin at or left of the position but the root, so nothing is skipped
+        let region = Region::new("chr1", 1, 100);
+        assert_eq!(index.chunks(&region), vec![(0, 100), (550, 700)]);
+    }
+
+    #[test]
+    fn region_diff() {
+        let mut cg = CompareGFF::new();
+        cg.region(Some(Region::new("chr1", 150000, 250000)));
+        cg.load_test_files("tabix_region", "base.gff.gz", "modified.gff.gz");
+        assert_eq!(cg.data1.as_ref().unwrap().len(), 20);
+        let diff = cg.diff().unwrap();
+        let mut ids: Vec<&str> = diff["changes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(|change| change["id"].as_str())
+            .collect();
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids, vec!["chr1_G010", "chr1_G010_E2", "chr1_G010_T"]);
+
+        // Same result without index
+        let mut cg = CompareGFF::new();
+        cg.region(Some(Region::new("chr1", 150000, 250000)));
+        let text = String::from_utf8(
+            BgzfReader::new(File::open("test/tabix_region/base.gff.gz").unwrap())
+                .read_to_end()
+                .unwrap(),
+        )
+        .unwrap();
+        cg.load(crate::GffSource::text(text), crate::DataSet::Base)
+            .unwrap();
+        assert_eq!(cg.data1.as_ref().unwrap().len(), 20);
+    }
+}
@@ -0,0 +1,126 @@
+use crate::diff_features::{change_values, changes_by_id, value_text, ChangedFeature};
+use crate::{CompareGFF, DataSet, HashGFF};
+use bio::io::gff;
+use serde_json::value::Value;
+use std::collections::HashMap;
+use std::error::Error;
+
+const TABLE_COLUMNS: [&str; 11] = [
+    "id",
+    "seqname",
+    "start",
+    "end",
+    "feature_type",
+    "what",
+    "action",
+    "key",
+    "old",
+    "new",
+    "root_gene",
+];
+
+impl CompareGFF {
+    /// Returns the ID of the root element (usually the gene) of a feature after the diff, following the
+    /// `Parent` attributes of the changed features, and of the unchanged ones in the data set.
+    pub(crate) fn table_root_id(
+        id: &str,
+        features: &HashMap<&str, &ChangedFeature>,
+        data: &HashGFF,
+    ) -> String {
+        let element = |id: &str| -> Option<&gff::Record> {
+            match features.get(id) {
+                Some(feature) => Some(feature.element()),
+                None => data.get(id),
+            }
+        };
+        let mut chain = vec![id.to_string()];
+        while let Some(parent_id) =
+            element(chain.last().unwrap()).and_then(|e| e.attributes().get("Parent"))
+        {
+            if chain.contains(parent_id) || element(parent_id).is_none() {
+                break; // circular ID chain, or missing parent
+            }
+            chain.push(parent_id.to_owned());
+        }
+        chain.pop().unwrap()
+    }
+
+    /// Renders a diff as a table, one row per change, with a header row.
+    /// Columns are the feature ID and location (after the change; before, for removed features), the change
+    /// (`what`, `action`, `key`, `old` and `new` value), and the root gene of the feature.
+    /// `delimiter` is `b'\t'` for TSV, or `b','` for CSV; values are quoted where necessary.
+    /// `base` is the data set the diff applies to (the target for reversed diffs).
+    pub fn diff_table(
+        &self,
+        diff: &Value,
+        base: DataSet,
+        delimiter: u8,
+    ) -> Result<String, Box<dyn Error>> {
+        let data = self.loaded_data(base)?;
+        let changed_features = self.diff_features(diff, base)?;
+        let features = ChangedFeature::by_id(&changed_features);
+        let mut root_ids: HashMap<&str, String> = HashMap::new();
+        let changes = changes_by_id(diff);
+
+        let mut writer = csv::WriterBuilder::new()
+            .delimiter(delimiter)
+            .from_writer(vec![]);
+        writer.write_record(TABLE_COLUMNS)?;
+        for feature in &changed_features {
+            let e = feature.element();
+            let root_id = root_ids
+                .entry(&feature.id)
+                .or_insert_with(|| Self::table_root_id(&feature.id, &features, data));
+            for change in changes.get(feature.id.as_str()).into_iter().flatten() {
+                let (old, new) = change_values(change);
+                writer.write_record(&[
+                    feature.id.to_owned(),
+                    e.seqname().to_string(),
+                    e.start().to_string(),
+                    e.end().to_string(),
+                    e.feature_type().to_string(),
+                    value_text(&change["what"]),
+                    value_text(&change["action"]),
+                    value_text(&change["key"]),
+                    old,
+                    new,
+                    root_id.to_owned(),
+                ])?;
+            }
+        }
+        Ok(String::from_utf8(writer.into_inner()?)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diff_table(dir: &str, delimiter: u8) -> String {
+        let mut cg = CompareGFF::new();
+        cg.load_test_files(dir, "core.gff", "cap.gff");
+        let diff = cg.diff().unwrap();
+        cg.diff_table(&diff, DataSet::Base, delimiter).unwrap()
+    }
+
+    #[test]
+    fn diff_tsv() {
+        let tsv = diff_table("gene_split", b'\t');
+        let lines: Vec<&str> = tsv.lines().collect();
+        assert_eq!(lines[0], TABLE_COLUMNS.join("\t"));
+        assert!(
+            lines.contains(&"G0001\tScaffold0\t1\t500\tgene\trow\tupdate\tend\t900\t500\tG0001")
+        );
+        assert!(lines.contains(
+            &"C0004\tScaffold0\t600\t700\tCDS\tattribute\tremove\tParent\tM0001\t\tG0002"
+        ));
+        assert!(lines.contains(&"M0002\tScaffold0\t600\t900\tmRNA\trow\tadd\t\t\t\tG0002"));
+    }
+
+    #[test]
+    fn diff_csv() {
+        let csv = diff_table("alter_exon", b',');
+        assert!(csv.starts_with("id,seqname,start,end,"));
+        assert!(csv.contains("\nE0005,Scaffold0,800,1200,exon,row,update,end,900,1200,G0001\n"));
+    }
+}
@@ -0,0 +1,176 @@
+use crate::diff_features::COLUMN_NAMES;
+use crate::{CompareGFF, DataSet};
+use bio::io::gff;
+use serde_json::value::Value;
+use std::error::Error;
+
+const COLOR_HEADER: &str = "\x1b[36m";
+const COLOR_REMOVED: &str = "\x1b[31m";
+const COLOR_ADDED: &str = "\x1b[32m";
+const HIGHLIGHT_ON: &str = "\x1b[1;4m";
+const HIGHLIGHT_OFF: &str = "\x1b[22;24m";
+const COLOR_RESET: &str = "\x1b[0m";
+
+impl CompareGFF {
+    /// Renders a diff as human-readable text, grouped per feature like `diff -u`.
+    /// Each feature gets a header with a summary of its changes, followed by the old (`-`) and new (`+`) GFF line.
+    /// `base` is the data set the diff applies to (the target for reversed diffs).
+    /// With `color`, lines are colored with ANSI codes, and changed columns and attributes are highlighted.
+    pub fn diff_text(
+        &self,
+        diff: &Value,
+        base: DataSet,
+        color: bool,
+    ) -> Result<String, Box<dyn Error>> {
+        let features = self.diff_features(diff, base)?;
+
+        let mut ret = String::new();
+        for feature in &features {
+            let old_fields = feature.old.as_ref().map(Self::text_fields);
+            let new_fields = feature.new.as_ref().map(Self::text_fields);
+            let e = feature.old.as_ref().or(feature.new.as_ref()).unwrap();
+            let header = format!(
+                "@@ {} {} {}:{}-{} @@ {}",
+                feature.id,
+                e.feature_type(),
+                e.seqname(),
+                e.start(),
+                e.end(),
+                Self::text_summary(&old_fields, &new_fields)
+            );
+            ret += &match color {
+                true => format!("{}{}{}\n", COLOR_HEADER, header, COLOR_RESET),
+                false => format!("{}\n", header),
+            };
+            if let Some(fields) = &old_fields {
+                ret += &Self::text_line('-', fields, &new_fields, color);
+            }
+            if let Some(fields) = &new_fields {
+                ret += &Self::text_line('+', fields, &old_fields, color);
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Returns the columns of a GFF line, with the attributes split into `key=value` pairs.
+    fn text_fields(e: &gff::Record) -> (Vec<String>, Vec<String>) {
+        let columns = vec![
+            e.seqname().to_string(),
+            e.source().to_string(),
+            e.feature_type().to_string(),
+            e.start().to_string(),
+            e.end().to_string(),
+            Self::score_text(e),
+            Self::strand_symbol(e),
+            e.frame().to_string(),
+        ];
+        let mut keys: Vec<&String> = e.attributes().keys().collect();
+        keys.sort_by_key(|key| (*key != "ID", *key != "Parent", key.to_string()));
+        let attributes = keys
+            .iter()
+            .filter_map(|key| Some((key, e.attributes().get_vec(*key)?)))
+            .map(|(key, values)| format!("{}={}", key, values.join(",")))
+            .collect();
+        (columns, attributes)
+    }
+
+    /// Summarizes the differences between the old and new fields, e.g. `end: 900 -> 1200; +Note=x`.
+    fn text_summary(
+        old: &Option<(Vec<String>, Vec<String>)>,
+        new: &Option<(Vec<String>, Vec<String>)>,
+    ) -> String {
+        let (old, new) = match (old, new) {
+            (Some(old), Some(new)) => (old, new),
+            (Some(_), None) => return "removed".to_string(),
+            (None, Some(_)) => return "added".to_string(),
+            (None, None) => return String::new(),
+        };
+        let mut parts: Vec<String> = COLUMN_NAMES
+            .iter()
+            .zip(old.0.iter().zip(new.0.iter()))
+            .filter(|(_, (v1, v2))| v1 != v2)
+            .map(|(name, (v1, v2))| format!("{}: {} -> {}", name, v1, v2))
+            .collect();
+        old.1
+            .iter()
+            .filter(|a| !new.1.contains(a))
+            .for_each(|a| parts.push(format!("-{}", a)));
+        new.1
+            .iter()
+            .filter(|a| !old.1.contains(a))
+            .for_each(|a| parts.push(format!("+{}", a)));
+        parts.join("; ")
+    }
+
+    /// Renders a `-` or `+` line; with `color`, fields that differ from the other line are highlighted.
+    fn text_line(
+        prefix: char,
+        fields: &(Vec<String>, Vec<String>),
+        other: &Option<(Vec<String>, Vec<String>)>,
+        color: bool,
+    ) -> String {
+        let highlight = |value: &String, changed: bool| match color && changed {
+            true => format!("{}{}{}", HIGHLIGHT_ON, value, HIGHLIGHT_OFF),
+            false => value.to_owned(),
+        };
+        let columns: Vec<String> = fields
+            .0
+            .iter()
+            .enumerate()
+            .map(|(num, value)| {
+                let changed = other.as_ref().is_some_and(|o| o.0[num] != *value);
+                highlight(value, changed)
+            })
+            .collect();
+        let attributes: Vec<String> = fields
+            .1
+            .iter()
+            .map(|value| {
+                let changed = other.as_ref().is_some_and(|o| !o.1.contains(value));
+                highlight(value, changed)
+            })
+            .collect();
+        let line = format!("{}{}\t{}", prefix, columns.join("\t"), attributes.join(";"));
+        match (color, prefix) {
+            (true, '-') => format!("{}{}{}\n", COLOR_REMOVED, line, COLOR_RESET),
+            (true, _) => format!("{}{}{}\n", COLOR_ADDED, line, COLOR_RESET),
+            (false, _) => format!("{}\n", line),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diff_text(dir: &str, color: bool) -> String {
+        let mut cg = CompareGFF::new();
+        cg.load_test_files(dir, "core.gff", "cap.gff");
+        let diff = cg.diff().unwrap();
+        cg.diff_text(&diff, DataSet::Base, color).unwrap()
+    }
+
+    #[test]
+    fn text_diff() {
+        let text = diff_text("alter_exon", false);
+        let lines: Vec<&str> = text.lines().collect();
+        assert!(lines.contains(&"@@ E0005 exon Scaffold0:800-900 @@ end: 900 -> 1200"));
+        assert!(lines.contains(&"-Scaffold0\t.\texon\t800\t900\t.\t+\t.\tID=E0005;Parent=M0001"));
+        assert!(lines.contains(&"+Scaffold0\t.\texon\t800\t1200\t.\t+\t.\tID=E0005;Parent=M0001"));
+        assert!(!text.contains('\x1b'));
+    }
+
+    #[test]
+    fn text_diff_added_removed() {
+        let text = diff_text("added_exon", false);
+        assert!(text.contains(" @@ added\n+Scaffold0\t"));
+        let text = diff_text("remove_exon", true);
+        assert!(text.contains(" @@ removed\x1b[0m\n\x1b[31m-Scaffold0\t"));
+    }
+
+    #[test]
+    fn text_diff_color() {
+        let text = diff_text("alter_exon", true);
+        assert!(text.contains("\x1b[32m+Scaffold0\t.\texon\t800\t\x1b[1;4m1200\x1b[22;24m\t"));
+    }
+}